@@ -0,0 +1,53 @@
+//! Compares a frame of collision processing with the spatial grid broadphase
+//! against checking every entity against every other entity.
+//!
+//! Run with `cargo bench --bench broadphase`.
+
+extern crate splitterman;
+
+use std::time::Instant;
+use splitterman::engine::{Engine, SpatialGrid};
+use splitterman::entity::{Entity, SplitterMan};
+use splitterman::input::QueuedInput;
+
+/// Make a headless engine with `n` size 1 splitter men scattered pseudo
+/// randomly (but the same every run) over a square.
+fn make_engine(n: usize) -> Engine {
+  let mut engine = Engine::new_headless(800, 600, Box::new(QueuedInput::new()));
+  let side = (n as f32).sqrt() * 24.0;
+  let mut seed: u32 = 12345;
+  let mut rand = || {
+    seed = seed.wrapping_mul(1103515245).wrapping_add(12345);
+    (seed >> 8) as f32 / (1 << 24) as f32
+  };
+  for _ in 0..n {
+    let (x, y) = (rand() * side, rand() * side);
+    engine.add_entity(Entity::SplitterMan(SplitterMan::new(x, y, 1)));
+  }
+  engine
+}
+
+/// Run `frames` frames. Returns the average time per frame in milliseconds.
+fn time_frames(engine: &mut Engine, frames: usize) -> f64 {
+  let start = Instant::now();
+  for _ in 0..frames { engine.tick(); }
+  let elapsed = start.elapsed();
+  (elapsed.as_secs() as f64 * 1000.0 + elapsed.subsec_nanos() as f64 / 1e6) / frames as f64
+}
+
+fn main() {
+  println!("{:>8} {:>14} {:>14}", "entities", "brute (ms)", "grid (ms)");
+  for &n in &[100, 1000, 10000] {
+    let frames = (10000 / n).max(2);
+
+    let mut brute = make_engine(n);
+    brute.spatial_grid = None;
+    let brute_ms = time_frames(&mut brute, frames);
+
+    let mut grid = make_engine(n);
+    grid.spatial_grid = Some(SpatialGrid::new(64.0));
+    let grid_ms = time_frames(&mut grid, frames);
+
+    println!("{:>8} {:>14.3} {:>14.3}", n, brute_ms, grid_ms);
+  }
+}
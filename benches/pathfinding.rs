@@ -0,0 +1,59 @@
+//! Compares finding paths for a group of splitter men heading to the same
+//! place with A* for each of them, against finding one flow field and
+//! following it for each of them.
+//!
+//! Run with `cargo bench --bench pathfinding`.
+
+extern crate splitterman;
+
+use std::time::Instant;
+use splitterman::engine::Vec2f32;
+use splitterman::engine::nav::NavGrid;
+use splitterman::level::Level;
+
+/// A big level with rows of walls to find a way through.
+const LEVEL: &str = "
+bounds 0 0 2000 1200
+box 400 0 40 900
+box 800 300 40 900
+box 1200 0 40 900
+box 1600 300 40 900
+spawn 100 100 1
+";
+
+/// Get `n` start points scattered pseudo randomly (but the same every run)
+/// over the left of the level.
+fn make_starts(n: usize) -> Vec<Vec2f32> {
+  let mut seed: u32 = 12345;
+  let mut rand = || {
+    seed = seed.wrapping_mul(1103515245).wrapping_add(12345);
+    (seed >> 8) as f32 / (1 << 24) as f32
+  };
+  (0..n).map(|_| Vec2f32(20.0 + rand() * 360.0, 20.0 + rand() * 1160.0)).collect()
+}
+
+/// Time a closure. Returns the time taken in milliseconds.
+fn time<F: FnMut()>(mut f: F) -> f64 {
+  let start = Instant::now();
+  f();
+  let elapsed = start.elapsed();
+  elapsed.as_secs() as f64 * 1000.0 + elapsed.subsec_nanos() as f64 / 1e6
+}
+
+fn main() {
+  let level = Level::parse(LEVEL).unwrap();
+  let grid = NavGrid::new(&level.bounds, &level.obstacles, 8.0);
+  let to = Vec2f32(1900.0, 600.0);
+  println!("{:>8} {:>14} {:>14}", "units", "a* (ms)", "flow (ms)");
+  for &n in &[10, 100, 1000] {
+    let starts = make_starts(n);
+    let astar_ms = time(|| {
+      for s in &starts { grid.find_path(*s, to).unwrap(); }
+    });
+    let flow_ms = time(|| {
+      let field = grid.flow_field(to).unwrap();
+      for s in &starts { grid.path_from_field(&field, *s, to).unwrap(); }
+    });
+    println!("{:>8} {:>14.3} {:>14.3}", n, astar_ms, flow_ms);
+  }
+}
@@ -0,0 +1,185 @@
+use engine::{Engine, Vec2f32};
+use engine::common::geometry::Shape;
+use entity::{Entity, ObstacleKind};
+use entity::splitter_man::State;
+
+const FNV_OFFSET: u64 = 0xcbf29ce484222325;
+const FNV_PRIME: u64 = 0x100000001b3;
+
+/// 64 bit FNV-1a hash. Unlike the standard library's hashers, it gives the
+/// same result on every platform and Rust release, so checksums can be
+/// compared between builds.
+pub struct Checksum(u64);
+
+impl Default for Checksum {
+  fn default() -> Checksum { Checksum::new() }
+}
+
+impl Checksum {
+  pub fn new() -> Checksum { Checksum(FNV_OFFSET) }
+
+  pub fn write_u8(&mut self, b: u8) {
+    self.0 ^= b as u64;
+    self.0 = self.0.wrapping_mul(FNV_PRIME);
+  }
+
+  pub fn write_u32(&mut self, n: u32) {
+    for b in &n.to_le_bytes() { self.write_u8(*b); }
+  }
+
+  pub fn write_u64(&mut self, n: u64) {
+    for b in &n.to_le_bytes() { self.write_u8(*b); }
+  }
+
+  /// Hash the exact bits of a float, so any difference changes the hash.
+  pub fn write_f32(&mut self, f: f32) { self.write_u32(f.to_bits()); }
+
+  pub fn write_vec(&mut self, v: Vec2f32) {
+    self.write_f32(v.0);
+    self.write_f32(v.1);
+  }
+
+  pub fn write_opt_vec(&mut self, v: Option<Vec2f32>) {
+    match v {
+      Some(v) => { self.write_u8(1); self.write_vec(v); },
+      None => self.write_u8(0),
+    }
+  }
+
+  pub fn finish(&self) -> u64 { self.0 }
+}
+
+impl Engine {
+  /// Hash of the exact state of every entity, for checking two simulations
+  /// haven't diverged. Stable across platforms and Rust releases.
+  pub fn checksum(&self) -> u64 {
+    let mut hash = Checksum::new();
+    hash.write_u64(self.tick_count);
+    for e in &self.entity_list {
+      let e = e.get();
+      let h = e.get_entity_handle().unwrap();
+      hash.write_u32(h.0);
+      hash.write_u32(h.1);
+      match e {
+        Entity::SplitterMan(s) => {
+          hash.write_u8(0);
+          hash.write_vec(s.body.pos);
+          hash.write_vec(s.body.vel);
+          hash.write_f32(s.body.rad);
+          hash.write_u32(s.size);
+          hash.write_u8(s.selected as u8);
+          hash.write_u32(s.groups as u32);
+          hash.write_f32(s.speed);
+          hash.write_opt_vec(s.target);
+          hash.write_opt_vec(s.group_target);
+          if let Some(ref p) = s.path {
+            hash.write_u32(p.next_index() as u32);
+            for pt in p.points() { hash.write_vec(*pt); }
+          }
+          for m in s.queue.moves() {
+            hash.write_vec(m.target);
+            hash.write_opt_vec(m.group_target);
+          }
+          match s.state {
+            State::Idle(_) => hash.write_u8(0),
+            State::Moving(_) => hash.write_u8(1),
+            State::Splitting(st) => {
+              hash.write_u8(2);
+              hash.write_u32(st.tick);
+              hash.write_u32(st.duration);
+              hash.write_vec(st.from);
+              hash.write_vec(st.to);
+              hash.write_f32(st.from_rad);
+              hash.write_f32(st.to_rad);
+            },
+            State::Joining(st) => {
+              hash.write_u8(3);
+              hash.write_u32(st.leader.0);
+              hash.write_u32(st.leader.1);
+              hash.write_u32(st.tick);
+            },
+            State::Holding(_) => hash.write_u8(4),
+            State::Patrolling(_) => hash.write_u8(5),
+          }
+        },
+        Entity::Obstacle(o) => {
+          hash.write_u8(1);
+          match o.shape {
+            Shape::Rect(r) => { hash.write_vec(r.pos); hash.write_vec(r.size); },
+            Shape::Poly(ref poly) => for v in poly.verts() { hash.write_vec(*v); },
+          }
+          match o.kind {
+            ObstacleKind::Wall => hash.write_u8(0),
+            ObstacleKind::Gap(max_rad) => { hash.write_u8(1); hash.write_f32(max_rad); },
+            ObstacleKind::Door { channel, open } => {
+              hash.write_u8(2);
+              hash.write_u32(channel);
+              hash.write_u8(open as u8);
+            },
+          }
+        },
+        Entity::PressurePlate(p) => {
+          hash.write_u8(2);
+          hash.write_vec(p.area.pos);
+          hash.write_vec(p.area.size);
+          hash.write_u8(p.pressed as u8);
+        },
+      }
+    }
+    return hash.finish();
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use glium::glutin::{Event, ElementState, MouseButton};
+  use engine::clock::ManualClock;
+  use input::QueuedInput;
+  use level::Level;
+
+  const LEVEL: &str = "bounds 0 0 800 600\nbox 300 200 50 200\nspawn 100 300 4\nspawn 140 300 2\n";
+
+  #[test]
+  fn fnv_matches_reference() {
+    // Reference values for FNV-1a 64
+    assert_eq!(Checksum::new().finish(), 0xcbf29ce484222325);
+    let mut h = Checksum::new();
+    for b in b"a" { h.write_u8(*b); }
+    assert_eq!(h.finish(), 0xaf63dc4c8601ec8c);
+  }
+
+  /// Run an engine through the same clicks, with the clock advancing a frame
+  /// at a time.
+  fn run() -> (Engine, Vec<u64>) {
+    let input = QueuedInput::new();
+    let clock = ManualClock::new();
+    let mut e = Engine::new_headless(800, 600, Box::new(input.clone()));
+    e.set_clock(Box::new(clock.clone()));
+    e.load_level(Level::parse(LEVEL).unwrap());
+    e.select_all();
+    let mut sums = Vec::new();
+    for frame in 0..120 {
+      if frame == 5 {
+        input.push(Event::MouseMoved(600, 300));
+        input.push(Event::MouseInput(ElementState::Pressed, MouseButton::Right));
+      }
+      if frame == 6 { input.push(Event::MouseInput(ElementState::Released, MouseButton::Right)); }
+      clock.advance(e.nanos_per_frame);
+      e.update();
+      sums.push(e.checksum());
+    }
+    return (e, sums);
+  }
+
+  #[test]
+  fn same_input_same_checksum() {
+    let (a, a_sums) = run();
+    let (b, b_sums) = run();
+    assert_eq!(a.tick_count, 120);
+    assert_eq!(a_sums, b_sums);
+    assert_eq!(a.checksum(), b.checksum());
+    // The units actually went somewhere
+    assert!(a_sums[0] != a.checksum());
+  }
+}
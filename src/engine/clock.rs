@@ -0,0 +1,35 @@
+use std::cell::Cell;
+use std::rc::Rc;
+use time;
+
+/// Source of time for the engine. The engine only uses this to decide how
+/// many simulation frames to run, so the simulation itself never depends on
+/// the time.
+pub trait Clock {
+  /// Current time in nanoseconds. Only differences between calls matter.
+  fn now_nanos(&mut self) -> u64;
+}
+
+/// Clock reading the system's high precision timer.
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+  fn now_nanos(&mut self) -> u64 { time::precise_time_ns() }
+}
+
+/// Clock which only moves when told to. Clones share the same time, so keep a
+/// clone around to advance it after giving one to the engine.
+#[derive(Clone, Default)]
+pub struct ManualClock {
+  nanos: Rc<Cell<u64>>,
+}
+
+impl ManualClock {
+  pub fn new() -> ManualClock { ManualClock { nanos: Rc::new(Cell::new(0)) } }
+
+  pub fn advance(&self, nanos: u64) { self.nanos.set(self.nanos.get() + nanos); }
+}
+
+impl Clock for ManualClock {
+  fn now_nanos(&mut self) -> u64 { self.nanos.get() }
+}
@@ -0,0 +1,133 @@
+use entity::EHandle;
+use engine::Vec2f32;
+use engine::common::geometry::Shape;
+
+/// A circle taking part in collision resolution.
+#[derive(Clone, Copy, Debug)]
+pub struct ContactBody {
+  pub handle: EHandle,
+  pub pos: Vec2f32,
+  pub rad: f32,
+  /// 1 / mass. 0 means the body can't be pushed by other bodies, though
+  /// shapes still push it out.
+  pub inv_mass: f32,
+}
+
+/// Push overlapping bodies apart. Every overlap is resolved at the same time
+/// each iteration (each body moves by the average of its corrections), so the
+/// result doesn't depend on the order of the bodies. Heavier bodies are
+/// pushed less than lighter ones. After the bodies push each other, they are
+/// pushed out of any static shapes they overlap, whatever their mass, which
+/// only moves them along the shape's normal so they slide along walls.
+/// # Params
+/// * `bodies`      - The bodies to resolve. Positions are updated in place.
+/// * `pairs`       - Indices into `bodies` of every pair which might overlap.
+/// * `shapes`      - Immovable shapes.
+/// * `shape_pairs` - Indices into `bodies` and `shapes` of every body and
+///   shape which might overlap.
+/// * `iterations`  - Maximum number of relaxation passes to run.
+pub fn solve(bodies: &mut [ContactBody], pairs: &[(usize, usize)], 
+             shapes: &[Shape], shape_pairs: &[(usize, usize)], iterations: u32) {
+  let mut deltas = vec![Vec2f32(0.0, 0.0); bodies.len()];
+  let mut counts = vec![0u32; bodies.len()];
+  for _ in 0..iterations {
+    let mut any = false;
+    for &(i, j) in pairs {
+      let (a, b) = (bodies[i], bodies[j]);
+      let total_inv_mass = a.inv_mass + b.inv_mass;
+      if total_inv_mass <= 0.0 { continue; }
+      let mut n = b.pos - a.pos;
+      let dis = n.len();
+      let overlap = a.rad + b.rad - dis;
+      if overlap <= 0.0 { continue; }
+      // Bodies exactly on top of each other, just pick a direction
+      if dis > 0.0 { n.nor(); } else { n = Vec2f32(1.0, 0.0); }
+      deltas[i] -= *Vec2f32::new_from_copy(&n).scale(overlap * a.inv_mass / total_inv_mass);
+      deltas[j] += *n.scale(overlap * b.inv_mass / total_inv_mass);
+      counts[i] += 1;
+      counts[j] += 1;
+      any = true;
+    }
+    for (ii, b) in bodies.iter_mut().enumerate() {
+      if counts[ii] > 0 {
+        b.pos += *deltas[ii].scale(1.0 / counts[ii] as f32);
+      }
+      deltas[ii] = Vec2f32(0.0, 0.0);
+      counts[ii] = 0;
+    }
+    for &(i, s) in shape_pairs {
+      if let Some(push) = shapes[s].push_out(bodies[i].pos, bodies[i].rad) {
+        bodies[i].pos += push;
+        any = true;
+      }
+    }
+    if !any { break; }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn body(ix: u32, x: f32, y: f32, rad: f32, inv_mass: f32) -> ContactBody {
+    ContactBody { handle: EHandle(ix, 0), pos: Vec2f32(x, y), rad, inv_mass }
+  }
+
+  /// Every pair of bodies.
+  fn all_pairs(n: usize) -> Vec<(usize, usize)> {
+    let mut pairs = Vec::new();
+    for i in 0..n { for j in i + 1..n { pairs.push((i, j)); } }
+    return pairs;
+  }
+
+  fn overlap(a: &ContactBody, b: &ContactBody) -> f32 { a.rad + b.rad - (b.pos - a.pos).len() }
+
+  #[test]
+  fn order_doesnt_matter() {
+    let bodies = vec![body(0, 0.0, 0.0, 10.0, 1.0), body(1, 15.0, 2.0, 10.0, 0.5), body(2, 28.0, -3.0, 8.0, 1.0)];
+    let mut forwards = bodies.clone();
+    solve(&mut forwards, &[(0, 1), (1, 2)], &[], &[], 4);
+    // Same bodies and pairs, every list backwards
+    let mut backwards: Vec<ContactBody> = bodies.iter().rev().cloned().collect();
+    solve(&mut backwards, &[(1, 0), (2, 1)], &[], &[], 4);
+    backwards.reverse();
+    for (f, b) in forwards.iter().zip(backwards.iter()) {
+      assert_eq!(f.handle, b.handle);
+      assert_eq!(f.pos, b.pos);
+    }
+    assert!(forwards[0].pos != bodies[0].pos);
+  }
+
+  #[test]
+  fn heavy_bodies_move_less() {
+    // Sizes 1 and 4
+    let mut bodies = vec![body(0, 0.0, 0.0, 10.0, 1.0), body(1, 15.0, 0.0, 10.0, 0.25)];
+    solve(&mut bodies, &[(0, 1)], &[], &[], 4);
+    let small = bodies[0].pos.len();
+    let big = (bodies[1].pos - Vec2f32(15.0, 0.0)).len();
+    assert!((small - 4.0).abs() < 0.001 && (big - 1.0).abs() < 0.001, "{} {}", small, big);
+    assert!(overlap(&bodies[0], &bodies[1]) < 0.001);
+  }
+
+  #[test]
+  fn immovable_bodies_stay_put() {
+    let mut bodies = vec![body(0, 0.0, 0.0, 10.0, 0.0), body(1, 15.0, 0.0, 10.0, 1.0)];
+    solve(&mut bodies, &[(0, 1)], &[], &[], 4);
+    assert_eq!(bodies[0].pos, Vec2f32(0.0, 0.0));
+    assert!((bodies[1].pos - Vec2f32(20.0, 0.0)).len() < 0.001);
+    // Two of them just overlap
+    let mut bodies = vec![body(0, 0.0, 0.0, 10.0, 0.0), body(1, 15.0, 0.0, 10.0, 0.0)];
+    solve(&mut bodies, &[(0, 1)], &[], &[], 4);
+    assert_eq!(bodies[1].pos, Vec2f32(15.0, 0.0));
+  }
+
+  #[test]
+  fn clumps_separate() {
+    let mut bodies = vec![body(0, 0.0, 0.0, 10.0, 1.0), body(1, 6.0, 1.0, 10.0, 1.0), body(2, 3.0, 5.0, 10.0, 1.0)];
+    let pairs = all_pairs(bodies.len());
+    solve(&mut bodies, &pairs, &[], &[], 16);
+    for &(i, j) in &pairs {
+      assert!(overlap(&bodies[i], &bodies[j]) < 0.01, "{:?} and {:?} overlap", bodies[i], bodies[j]);
+    }
+  }
+}
@@ -0,0 +1,39 @@
+use entity::EHandle;
+use engine::Vec2f32;
+
+/// Something for splitter men to do.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Order {
+  /// Move to a point, each splitter man taking a spot around it in the
+  /// engine's current formation. If `queue` is set, the move is made after
+  /// any moves already ordered, rather than replacing them.
+  Move { dest: Vec2f32, queue: bool },
+  /// Split into two splitter men of half the size.
+  Split,
+  /// Walk together and merge into one splitter man.
+  Join,
+  /// Stop moving and forget any orders.
+  Stop,
+  /// Stop, and stay put until given another order, even when shoved.
+  Hold,
+  /// Walk between the points in a loop, forever. Given just one point,
+  /// walk between it and where the splitter men are now.
+  Patrol(Vec<Vec2f32>),
+}
+
+/// An order given to a set of splitter men. The player's input, replays and
+/// anything else controlling splitter men all go through commands, which
+/// the engine carries out at the start of each frame.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Command {
+  /// The splitter men to order, in any order. Handles to entities which no
+  /// longer exist, or aren't splitter men, are ignored.
+  pub units: Vec<EHandle>,
+  pub order: Order,
+}
+
+impl Command {
+  pub fn new(units: Vec<EHandle>, order: Order) -> Command {
+    Command { units, order }
+  }
+}
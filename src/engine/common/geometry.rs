@@ -0,0 +1,372 @@
+use std::f32::consts::PI;
+use engine::Vec2f32;
+
+/// Most vertices a ConvexPoly can have. Polygons are stored inline so that
+/// entities holding them can stay Copy.
+pub const MAX_POLY_VERTS: usize = 8;
+
+/// Axis aligned rectangle. `pos` is the corner with the smallest coords.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Rect {
+  pub pos: Vec2f32,
+  pub size: Vec2f32,
+}
+
+impl Rect {
+  pub fn new(x: f32, y: f32, w: f32, h: f32) -> Rect {
+    Rect { pos: Vec2f32(x, y), size: Vec2f32(w, h) }
+  }
+
+  /// The corner with the largest coords.
+  pub fn max(&self) -> Vec2f32 { self.pos + self.size }
+
+  pub fn contains(&self, p: Vec2f32) -> bool {
+    let max = self.max();
+    p.0 >= self.pos.0 && p.0 <= max.0 && p.1 >= self.pos.1 && p.1 <= max.1
+  }
+
+  pub fn centre(&self) -> Vec2f32 { self.pos + *Vec2f32::new_from_copy(&self.size).scale(0.5) }
+
+  /// How far a circle needs to move to stop overlapping this rect, or None if
+  /// it doesn't overlap.
+  pub fn push_out(&self, p: Vec2f32, rad: f32) -> Option<Vec2f32> {
+    let max = self.max();
+    let closest = Vec2f32(p.0.max(self.pos.0).min(max.0), p.1.max(self.pos.1).min(max.1));
+    let mut d = p - closest;
+    let dis = d.len();
+    if dis > 0.0 {
+      if dis >= rad { return None; }
+      return Some(*d.nor().scale(rad - dis));
+    }
+    // Centre is inside the rect, push out of the nearest side
+    let sides = [(p.0 - self.pos.0, Vec2f32(-1.0, 0.0)), (max.0 - p.0, Vec2f32(1.0, 0.0)),
+                 (p.1 - self.pos.1, Vec2f32(0.0, -1.0)), (max.1 - p.1, Vec2f32(0.0, 1.0))];
+    let mut nearest = sides[0];
+    for s in &sides[1..] { if s.0 < nearest.0 { nearest = *s; } }
+    return Some(*nearest.1.scale(nearest.0 + rad));
+  }
+
+  /// True if a circle is entirely inside this rect.
+  pub fn contains_circle(&self, p: Vec2f32, rad: f32) -> bool {
+    let max = self.max();
+    p.0 - rad >= self.pos.0 && p.0 + rad <= max.0 
+      && p.1 - rad >= self.pos.1 && p.1 + rad <= max.1
+  }
+}
+
+/// Convex polygon, with vertices in either winding order.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ConvexPoly {
+  verts: [Vec2f32; MAX_POLY_VERTS],
+  len: usize,
+}
+
+impl ConvexPoly {
+  /// Make a polygon from a list of points.
+  /// # Returns
+  /// None if there are fewer than 3 or more than MAX_POLY_VERTS points, or if
+  /// the points don't make a convex polygon, including stars which turn the
+  /// same way at every corner but cross over themselves.
+  pub fn new(points: &[Vec2f32]) -> Option<ConvexPoly> {
+    if points.len() < 3 || points.len() > MAX_POLY_VERTS { return None; }
+    // Every corner must turn the same way, and all together only go round
+    // once
+    let mut sign = 0.0;
+    let mut turned = 0.0;
+    for ii in 0..points.len() {
+      let (a, b, c) = (points[ii], points[(ii + 1) % points.len()], points[(ii + 2) % points.len()]);
+      let (ab, bc) = (b - a, c - b);
+      let cross = cross(ab, bc);
+      if cross == 0.0 || (sign != 0.0 && cross.signum() != sign) { return None; }
+      sign = cross.signum();
+      turned += cross.atan2(ab.0*bc.0 + ab.1*bc.1);
+    }
+    if (turned.abs() - 2.0*PI).abs() > 0.01 { return None; }
+    let mut verts = [Vec2f32(0.0, 0.0); MAX_POLY_VERTS];
+    verts[..points.len()].copy_from_slice(points);
+    return Some(ConvexPoly { verts, len: points.len() });
+  }
+
+  pub fn verts(&self) -> &[Vec2f32] { &self.verts[..self.len] }
+
+  /// Average of the vertices.
+  pub fn centre(&self) -> Vec2f32 {
+    let mut c = Vec2f32(0.0, 0.0);
+    for v in self.verts() { c += *v; }
+    return *c.scale(1.0 / self.len as f32);
+  }
+
+  /// How far a circle needs to move to stop overlapping this polygon, or None
+  /// if it doesn't overlap.
+  pub fn push_out(&self, p: Vec2f32, rad: f32) -> Option<Vec2f32> {
+    let verts = self.verts();
+    if !self.contains(p) {
+      // Find the closest point on the outline
+      let mut best: Option<Vec2f32> = None;
+      for ii in 0..verts.len() {
+        let c = closest_on_segment(verts[ii], verts[(ii + 1) % verts.len()], p);
+        if best.is_none() || (p - c).len2() < (p - best.unwrap()).len2() { best = Some(c); }
+      }
+      let mut d = p - best.unwrap();
+      let dis = d.len();
+      if dis >= rad || dis == 0.0 { return None; }
+      return Some(*d.nor().scale(rad - dis));
+    }
+    // Centre is inside the polygon, push out through the nearest edge
+    let winding = self.winding();
+    let mut best: Option<(f32, Vec2f32)> = None;
+    for ii in 0..verts.len() {
+      let (a, b) = (verts[ii], verts[(ii + 1) % verts.len()]);
+      let edge = b - a;
+      // Outward normal
+      let mut n = *Vec2f32(edge.1, -edge.0).scale(winding);
+      n.nor();
+      let depth = -((p.0 - a.0)*n.0 + (p.1 - a.1)*n.1);
+      if best.is_none() || depth < best.unwrap().0 { best = Some((depth, n)); }
+    }
+    let (depth, mut n) = best.unwrap();
+    return Some(*n.scale(depth + rad));
+  }
+
+  /// 1 if the vertices go anticlockwise (with y up), -1 if clockwise.
+  fn winding(&self) -> f32 {
+    let verts = self.verts();
+    return cross(verts[1] - verts[0], verts[2] - verts[1]).signum();
+  }
+
+  pub fn contains(&self, p: Vec2f32) -> bool {
+    let verts = self.verts();
+    let mut sign = 0.0;
+    for ii in 0..verts.len() {
+      let (a, b) = (verts[ii], verts[(ii + 1) % verts.len()]);
+      let cross = cross(b - a, p - a);
+      if cross == 0.0 { continue; }
+      if sign != 0.0 && cross.signum() != sign { return false; }
+      sign = cross.signum();
+    }
+    return true;
+  }
+}
+
+/// A solid shape in the world.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Shape {
+  Rect(Rect),
+  Poly(ConvexPoly),
+}
+
+impl Shape {
+  pub fn contains(&self, p: Vec2f32) -> bool {
+    match *self {
+      Shape::Rect(ref r) => r.contains(p),
+      Shape::Poly(ref poly) => poly.contains(p),
+    }
+  }
+
+  pub fn centre(&self) -> Vec2f32 {
+    match *self {
+      Shape::Rect(ref r) => r.centre(),
+      Shape::Poly(ref poly) => poly.centre(),
+    }
+  }
+
+  /// Radius of a circle around the centre containing the whole shape.
+  pub fn bounding_rad(&self) -> f32 {
+    let c = self.centre();
+    match *self {
+      Shape::Rect(ref r) => (r.max() - c).len(),
+      Shape::Poly(ref poly) => poly.verts().iter().map(|v| (*v - c).len()).fold(0.0, f32::max),
+    }
+  }
+
+  /// How far a circle needs to move to stop overlapping this shape, or None if
+  /// it doesn't overlap.
+  pub fn push_out(&self, p: Vec2f32, rad: f32) -> Option<Vec2f32> {
+    match *self {
+      Shape::Rect(ref r) => r.push_out(p, rad),
+      Shape::Poly(ref poly) => poly.push_out(p, rad),
+    }
+  }
+}
+
+/// Closest point to p on the line segment from a to b.
+pub fn closest_on_segment(a: Vec2f32, b: Vec2f32, p: Vec2f32) -> Vec2f32 {
+  let ab = b - a;
+  let len2 = ab.len2();
+  if len2 == 0.0 { return a; }
+  let t = (((p.0 - a.0)*ab.0 + (p.1 - a.1)*ab.1) / len2).clamp(0.0, 1.0);
+  return a.lerp(&b, t);
+}
+
+/// 2D cross product (the z of the 3D cross product).
+pub fn cross(a: Vec2f32, b: Vec2f32) -> f32 { a.0*b.1 - a.1*b.0 }
+
+/// True if p is inside a polygon, which can be concave or cross over itself.
+/// Where it crosses over itself, areas enclosed an even number of times are
+/// outside. Polygons with fewer than 3 points contain nothing.
+pub fn point_in_polygon(p: Vec2f32, poly: &[Vec2f32]) -> bool {
+  if poly.len() < 3 { return false; }
+  // Count the edges a ray going right from p crosses
+  let mut inside = false;
+  let mut prev = poly[poly.len() - 1];
+  for &v in poly {
+    if (v.1 > p.1) != (prev.1 > p.1) {
+      let x = v.0 + (p.1 - v.1) / (prev.1 - v.1) * (prev.0 - v.0);
+      if p.0 < x { inside = !inside; }
+    }
+    prev = v;
+  }
+  return inside;
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn point_in_square() {
+    let square = [Vec2f32(0.0, 0.0), Vec2f32(10.0, 0.0), Vec2f32(10.0, 10.0), Vec2f32(0.0, 10.0)];
+    assert!(point_in_polygon(Vec2f32(5.0, 5.0), &square));
+    assert!(point_in_polygon(Vec2f32(0.1, 9.9), &square));
+    assert!(!point_in_polygon(Vec2f32(-1.0, 5.0), &square));
+    assert!(!point_in_polygon(Vec2f32(11.0, 5.0), &square));
+    assert!(!point_in_polygon(Vec2f32(5.0, 10.5), &square));
+  }
+
+  #[test]
+  fn winding_order_doesnt_matter() {
+    let mut tri = vec![Vec2f32(0.0, 0.0), Vec2f32(10.0, 0.0), Vec2f32(0.0, 10.0)];
+    assert!(point_in_polygon(Vec2f32(2.0, 2.0), &tri));
+    tri.reverse();
+    assert!(point_in_polygon(Vec2f32(2.0, 2.0), &tri));
+    assert!(!point_in_polygon(Vec2f32(6.0, 6.0), &tri));
+  }
+
+  #[test]
+  fn point_in_concave() {
+    // A U shape, open at the top
+    let u = [Vec2f32(0.0, 0.0), Vec2f32(3.0, 0.0), Vec2f32(3.0, 7.0), Vec2f32(7.0, 7.0),
+             Vec2f32(7.0, 0.0), Vec2f32(10.0, 0.0), Vec2f32(10.0, 10.0), Vec2f32(0.0, 10.0)];
+    assert!(point_in_polygon(Vec2f32(1.0, 5.0), &u));
+    assert!(point_in_polygon(Vec2f32(9.0, 5.0), &u));
+    assert!(point_in_polygon(Vec2f32(5.0, 9.0), &u));
+    assert!(!point_in_polygon(Vec2f32(5.0, 3.0), &u));
+  }
+
+  #[test]
+  fn point_in_self_intersecting() {
+    // A bow tie, crossing over at (5, 5)
+    let bow = [Vec2f32(0.0, 0.0), Vec2f32(10.0, 10.0), Vec2f32(10.0, 0.0), Vec2f32(0.0, 10.0)];
+    assert!(point_in_polygon(Vec2f32(1.0, 5.0), &bow));
+    assert!(point_in_polygon(Vec2f32(9.0, 5.0), &bow));
+    assert!(!point_in_polygon(Vec2f32(5.0, 1.0), &bow));
+    assert!(!point_in_polygon(Vec2f32(5.0, 9.0), &bow));
+  }
+
+  #[test]
+  fn degenerate_polygons_contain_nothing() {
+    assert!(!point_in_polygon(Vec2f32(0.0, 0.0), &[]));
+    assert!(!point_in_polygon(Vec2f32(0.0, 0.0), &[Vec2f32(0.0, 0.0), Vec2f32(1.0, 1.0)]));
+    let flat = [Vec2f32(0.0, 0.0), Vec2f32(5.0, 0.0), Vec2f32(10.0, 0.0)];
+    assert!(!point_in_polygon(Vec2f32(5.0, 0.0), &flat));
+  }
+
+  /// Push a circle out of a shape, and check it ends up just touching it.
+  fn push_out_to_touching(shape: &Shape, p: Vec2f32, rad: f32) -> Vec2f32 {
+    let push = shape.push_out(p, rad).unwrap_or_else(|| panic!("{:?} not pushed out", p));
+    let q = p + push;
+    assert!(shape.push_out(q, rad - 0.001).is_none(), "{:?} still inside after push {:?}", p, push);
+    assert!(shape.push_out(q, rad + 0.001).is_some(), "{:?} pushed too far by {:?}", p, push);
+    return push;
+  }
+
+  fn close(a: Vec2f32, b: Vec2f32) -> bool { (a - b).len() < 0.001 }
+
+  /// A diamond, 10 from its centre at the origin to each corner.
+  fn diamond() -> ConvexPoly {
+    ConvexPoly::new(&[Vec2f32(0.0, -10.0), Vec2f32(10.0, 0.0), Vec2f32(0.0, 10.0), Vec2f32(-10.0, 0.0)]).unwrap()
+  }
+
+  #[test]
+  fn rect_pushes_out_of_each_edge_and_corner() {
+    let r = Shape::Rect(Rect::new(0.0, 0.0, 10.0, 10.0));
+    // Overlapping each edge, and with the centre just inside
+    assert!(close(push_out_to_touching(&r, Vec2f32(-1.0, 5.0), 2.0), Vec2f32(-1.0, 0.0)));
+    assert!(close(push_out_to_touching(&r, Vec2f32(11.0, 5.0), 2.0), Vec2f32(1.0, 0.0)));
+    assert!(close(push_out_to_touching(&r, Vec2f32(5.0, -1.0), 2.0), Vec2f32(0.0, -1.0)));
+    assert!(close(push_out_to_touching(&r, Vec2f32(5.0, 11.0), 2.0), Vec2f32(0.0, 1.0)));
+    assert!(close(push_out_to_touching(&r, Vec2f32(1.0, 5.0), 2.0), Vec2f32(-3.0, 0.0)));
+    assert!(close(push_out_to_touching(&r, Vec2f32(5.0, 9.5), 2.0), Vec2f32(0.0, 2.5)));
+    // Each corner pushes straight away from it
+    for &(x, y) in &[(0.0, 0.0), (10.0, 0.0), (0.0, 10.0), (10.0, 10.0)] {
+      let mut dir = Vec2f32(if x == 0.0 { -1.0 } else { 1.0 }, if y == 0.0 { -1.0 } else { 1.0 });
+      let push = push_out_to_touching(&r, Vec2f32(x + dir.0, y + dir.1), 2.0);
+      assert!(close(push, *dir.nor().scale(2.0 - 2f32.sqrt())));
+    }
+    assert!(r.push_out(Vec2f32(-3.0, 5.0), 2.0).is_none());
+    assert!(r.push_out(Vec2f32(-1.5, -1.5), 2.0).is_none());
+  }
+
+  #[test]
+  fn poly_pushes_out_of_each_edge_and_corner() {
+    let d = diamond();
+    let shape = Shape::Poly(d);
+    let verts = d.verts();
+    for ii in 0..verts.len() {
+      let (a, b) = (verts[ii], verts[(ii + 1) % verts.len()]);
+      let mid = a.lerp(&b, 0.5);
+      // Just outside, and just inside, the middle of each edge
+      let mut out = mid;
+      out.scale(1.1);
+      let push = push_out_to_touching(&shape, out, 2.0);
+      assert!((push.0*mid.0 + push.1*mid.1) > 0.0 && (push.0*mid.1 - push.1*mid.0).abs() < 0.001);
+      let mut inside = mid;
+      inside.scale(0.9);
+      push_out_to_touching(&shape, inside, 2.0);
+      // Off each corner, pushed straight away from it
+      let mut corner = a;
+      corner.scale(1.1);
+      let push = push_out_to_touching(&shape, corner, 2.0);
+      assert!(close(push, *Vec2f32::new_from_copy(&a).nor().scale(1.0)));
+    }
+    assert!(d.push_out(Vec2f32(20.0, 0.0), 2.0).is_none());
+  }
+
+  #[test]
+  fn circles_slide_along_walls() {
+    // Walking diagonally into the top of a wall only stops the downwards
+    // part of the move
+    let wall = Shape::Rect(Rect::new(0.0, 10.0, 100.0, 10.0));
+    let p = Vec2f32(53.0, 7.0);
+    let push = push_out_to_touching(&wall, p, 5.0);
+    assert!(close(p + push, Vec2f32(53.0, 5.0)));
+    // Same along the sloped edge of a polygon from (10, 0) to (0, 10). A
+    // circle touching its middle moving left slides up along it.
+    let shape = Shape::Poly(diamond());
+    let start = Vec2f32(5.0 + 2f32.sqrt(), 5.0 + 2f32.sqrt());
+    assert!(shape.push_out(start, 1.999).is_none());
+    let moved = start + Vec2f32(-2.0, 0.0);
+    let slid = moved + push_out_to_touching(&shape, moved, 2.0);
+    assert!(close(slid - start, Vec2f32(-1.0, 1.0)), "slid to {:?}", slid);
+  }
+
+  #[test]
+  fn convex_polys_only() {
+    let pentagon: Vec<Vec2f32> = (0..5).map(|ii| {
+      let a = ii as f32 * 2.0*PI / 5.0;
+      Vec2f32(a.cos() * 10.0, a.sin() * 10.0)
+    }).collect();
+    assert!(ConvexPoly::new(&pentagon).is_some());
+    let mut backwards = pentagon.clone();
+    backwards.reverse();
+    assert!(ConvexPoly::new(&backwards).is_some());
+    // The same points as a star turn the same way at every corner, but go
+    // round twice
+    let star: Vec<Vec2f32> = (0..5).map(|ii| pentagon[ii * 2 % 5]).collect();
+    assert!(ConvexPoly::new(&star).is_none());
+    // Concave, flat and too small
+    assert!(ConvexPoly::new(&[Vec2f32(0.0, 0.0), Vec2f32(10.0, 0.0), Vec2f32(5.0, 2.0), Vec2f32(5.0, 10.0)]).is_none());
+    assert!(ConvexPoly::new(&[Vec2f32(0.0, 0.0), Vec2f32(5.0, 0.0), Vec2f32(10.0, 0.0)]).is_none());
+    assert!(ConvexPoly::new(&[Vec2f32(0.0, 0.0), Vec2f32(5.0, 0.0)]).is_none());
+  }
+}
@@ -1 +1,2 @@
 pub mod vec;
+pub mod geometry;
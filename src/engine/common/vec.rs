@@ -59,6 +59,12 @@ impl Vec2f32 {
     return self;
   }
 
+  /// Linearly interpolate between this vector and another. t = 0 gives self,
+  /// t = 1 gives other.
+  pub fn lerp(&self, other: &Vec2f32, t: f32) -> Vec2f32 {
+    Vec2f32(self.0 + (other.0 - self.0)*t, self.1 + (other.1 - self.1)*t)
+  }
+
   /// Scale the vector by a given amount. Return a mutable reference to self,
   /// so you can chain functioins.
   pub fn scale(&mut self, amount: f32) -> &mut Vec2f32 {
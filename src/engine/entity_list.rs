@@ -0,0 +1,179 @@
+use std::cell::Cell;
+use entity::{Entity, EHandle};
+
+/// A slot in the entity list. The generation is bumped every time the entity
+/// in this slot is removed, so old handles to the slot stop working.
+struct Slot {
+  generation: u32,
+  entity: Option<Cell<Entity>>,
+}
+
+/// Generational arena of entities, keyed by EHandle. Lookup, insertion and
+/// removal are all O(1). Looking up a handle to an entity that has been
+/// removed returns None, even if the slot has since been reused.
+pub struct EntityList {
+  slots: Vec<Slot>,
+  /// Indices of empty slots, to be reused before growing the list
+  free: Vec<u32>,
+  len: usize,
+}
+
+impl Default for EntityList {
+  fn default() -> EntityList { EntityList::new() }
+}
+
+impl EntityList {
+  pub fn new() -> EntityList {
+    EntityList { slots: Vec::new(), free: Vec::new(), len: 0 }
+  }
+
+  /// Add an entity to the list, giving it a new handle.
+  /// # Returns
+  /// The handle of the new entity.
+  pub fn insert(&mut self, mut e: Entity) -> EHandle {
+    let h;
+    if let Some(ix) = self.free.pop() {
+      h = EHandle(ix, self.slots[ix as usize].generation);
+      e.set_entity_handle(h);
+      self.slots[ix as usize].entity = Some(Cell::new(e));
+    }
+    else {
+      h = EHandle(self.slots.len() as u32, 0);
+      e.set_entity_handle(h);
+      self.slots.push(Slot { generation: 0, entity: Some(Cell::new(e)) });
+    }
+    self.len += 1;
+    return h;
+  }
+
+  /// Remove the entity with the given handle.
+  /// # Returns
+  /// The removed entity, or None if the handle is stale.
+  pub fn remove(&mut self, h: EHandle) -> Option<Entity> {
+    self.get_cell(h)?;
+    let slot = &mut self.slots[h.0 as usize];
+    let e = slot.entity.take().unwrap().into_inner();
+    slot.generation = slot.generation.wrapping_add(1);
+    self.free.push(h.0);
+    self.len -= 1;
+    return Some(e);
+  }
+
+  /// Get the cell holding the entity with the given handle, or None if the
+  /// handle is stale.
+  pub fn get_cell(&self, h: EHandle) -> Option<&Cell<Entity>> {
+    match self.slots.get(h.0 as usize) {
+      Some(slot) if slot.generation == h.1 => slot.entity.as_ref(),
+      _ => None,
+    }
+  }
+
+  /// Get a copy of the entity with the given handle, or None if the handle is
+  /// stale.
+  pub fn get(&self, h: EHandle) -> Option<Entity> {
+    self.get_cell(h).map(|c| c.get())
+  }
+
+  pub fn get_mut(&mut self, h: EHandle) -> Option<&mut Entity> {
+    match self.slots.get_mut(h.0 as usize) {
+      Some(slot) if slot.generation == h.1 => slot.entity.as_mut().map(|c| c.get_mut()),
+      _ => None,
+    }
+  }
+
+  pub fn contains(&self, h: EHandle) -> bool { self.get_cell(h).is_some() }
+
+  pub fn len(&self) -> usize { self.len }
+  pub fn is_empty(&self) -> bool { self.len == 0 }
+
+  /// Every slot's generation and entity (None if the slot is empty), along
+  /// with the free list. Used for saving the list exactly.
+  pub fn to_raw(&self) -> (Vec<(u32, Option<Entity>)>, Vec<u32>) {
+    let slots = self.slots.iter().map(|s| (s.generation, s.entity.as_ref().map(|c| c.get()))).collect();
+    return (slots, self.free.clone());
+  }
+
+  /// Rebuild a list from the output of to_raw. Entities keep the handles
+  /// they already have.
+  /// # Returns
+  /// None if the free list doesn't match up with the empty slots.
+  pub fn from_raw(slots: Vec<(u32, Option<Entity>)>, free: Vec<u32>) -> Option<EntityList> {
+    let empty = slots.iter().filter(|s| s.1.is_none()).count();
+    let mut unique = free.clone();
+    unique.sort();
+    unique.dedup();
+    if free.len() != empty || unique.len() != empty { return None; }
+    for &ix in &free {
+      match slots.get(ix as usize) {
+        Some(&(_, None)) => (),
+        _ => return None,
+      }
+    }
+    let len = slots.len() - empty;
+    let slots = slots.into_iter()
+      .map(|(generation, e)| Slot { generation, entity: e.map(Cell::new) })
+      .collect();
+    return Some(EntityList { slots, free, len });
+  }
+
+  /// Iterate over the cells of all live entities, in slot order.
+  pub fn iter(&self) -> Iter<'_> {
+    Iter { slots: self.slots.iter() }
+  }
+}
+
+pub struct Iter<'a> {
+  slots: ::std::slice::Iter<'a, Slot>,
+}
+
+impl<'a> Iterator for Iter<'a> {
+  type Item = &'a Cell<Entity>;
+  fn next(&mut self) -> Option<&'a Cell<Entity>> {
+    for slot in &mut self.slots {
+      if slot.entity.is_some() { return slot.entity.as_ref(); }
+    }
+    return None;
+  }
+}
+
+impl<'a> IntoIterator for &'a EntityList {
+  type Item = &'a Cell<Entity>;
+  type IntoIter = Iter<'a>;
+  fn into_iter(self) -> Iter<'a> { self.iter() }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use entity::SplitterMan;
+
+  fn unit(size: u32) -> Entity { Entity::SplitterMan(SplitterMan::new(0.0, 0.0, size)) }
+
+  fn size_of(e: Option<Entity>) -> Option<u32> {
+    match e { Some(Entity::SplitterMan(s)) => Some(s.size), _ => None }
+  }
+
+  #[test]
+  fn stale_handle_after_reuse() {
+    let mut list = EntityList::new();
+    let old = list.insert(unit(1));
+    assert!(list.remove(old).is_some());
+    let new = list.insert(unit(2));
+    // Same slot, new generation
+    assert_eq!(new.0, old.0);
+    assert!(new != old);
+    assert!(list.get(old).is_none());
+    assert!(list.get_mut(old).is_none());
+    assert!(list.remove(old).is_none());
+    assert_eq!(size_of(list.get(new)), Some(2));
+    assert_eq!(list.len(), 1);
+  }
+
+  #[test]
+  fn inserted_entity_knows_its_handle() {
+    let mut list = EntityList::new();
+    list.insert(unit(1));
+    let h = list.insert(unit(2));
+    assert_eq!(list.get(h).unwrap().get_entity_handle(), Some(h));
+  }
+}
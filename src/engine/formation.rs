@@ -0,0 +1,190 @@
+use std::f32::consts::PI;
+use entity::EHandle;
+use engine::Vec2f32;
+
+/// Space left between neighbouring splitter men in a formation.
+const FORMATION_GAP: f32 = 4.0;
+
+/// Shape a group of splitter men arrange themselves in when moved together.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Formation {
+  /// Side by side, facing the way the group is moving.
+  Line,
+  /// Rows roughly as long as the box is deep.
+  Box,
+  /// Evenly spaced around a ring.
+  Circle,
+}
+
+/// A splitter man to place in a formation.
+#[derive(Clone, Copy, Debug)]
+pub struct FormationUnit {
+  pub handle: EHandle,
+  pub pos: Vec2f32,
+  pub rad: f32,
+}
+
+impl Formation {
+  /// The formation after this one, for cycling through them.
+  pub fn next(self) -> Formation {
+    match self {
+      Formation::Line => Formation::Box,
+      Formation::Box => Formation::Circle,
+      Formation::Circle => Formation::Line,
+    }
+  }
+
+  /// Give every unit its own spot around `dest`, spaced out by their radii.
+  /// The formation faces from the group's centre towards `dest`, and units
+  /// are given spots on the same side of the formation as they are of the
+  /// group, so the group keeps its layout and paths don't cross.
+  /// # Returns
+  /// The handle of each unit and its spot, in the same order as `units`.
+  pub fn slots(self, units: &[FormationUnit], dest: Vec2f32) -> Vec<(EHandle, Vec2f32)> {
+    if units.is_empty() { return Vec::new(); }
+    let mut centre = Vec2f32(0.0, 0.0);
+    for u in units { centre += u.pos; }
+    centre.scale(1.0 / units.len() as f32);
+    let mut forward = dest - centre;
+    if forward.len2() > 0.0 { forward.nor(); } else { forward = Vec2f32(0.0, -1.0); }
+    let right = Vec2f32(-forward.1, forward.0);
+    // Where each unit is in the group, relative to the way it's facing
+    let side = |u: &FormationUnit| { let d = u.pos - centre; d.0*right.0 + d.1*right.1 };
+    let depth = |u: &FormationUnit| { let d = u.pos - centre; d.0*forward.0 + d.1*forward.1 };
+
+    // Units level with each other go in handle order, so ties are always
+    // broken the same way
+    let by_handle = |a: usize, b: usize| units[a].handle.cmp(&units[b].handle);
+
+    // Offsets of each unit from dest, in (right, forward) coords
+    let mut order: Vec<usize> = (0..units.len()).collect();
+    let mut offsets = vec![Vec2f32(0.0, 0.0); units.len()];
+    match self {
+      Formation::Line => {
+        order.sort_by(|&a, &b| side(&units[a]).total_cmp(&side(&units[b])).then(by_handle(a, b)));
+        place_row(units, &order, 0.0, &mut offsets);
+      },
+      Formation::Box => {
+        // Front row first
+        order.sort_by(|&a, &b| depth(&units[b]).total_cmp(&depth(&units[a])).then(by_handle(a, b)));
+        let cols = (units.len() as f32).sqrt().ceil() as usize;
+        let mut y = 0.0;
+        let mut row_ys = Vec::new();
+        for row in order.chunks_mut(cols) {
+          row.sort_by(|&a, &b| side(&units[a]).total_cmp(&side(&units[b])).then(by_handle(a, b)));
+          let row_rad = row.iter().map(|&ii| units[ii].rad).fold(0.0, f32::max);
+          if !row_ys.is_empty() { y -= row_rad; }
+          place_row(units, row, y, &mut offsets);
+          row_ys.push(y);
+          y -= row_rad + FORMATION_GAP;
+        }
+        // Centre the rows on dest
+        let mid = (row_ys[0] + row_ys[row_ys.len() - 1]) * 0.5;
+        for o in &mut offsets { o.1 -= mid; }
+      },
+      Formation::Circle => {
+        if units.len() > 1 {
+          let angle = |u: &FormationUnit| depth(u).atan2(side(u));
+          order.sort_by(|&a, &b| angle(&units[a]).total_cmp(&angle(&units[b])).then(by_handle(a, b)));
+          let circumference: f32 = units.iter().map(|u| u.rad*2.0 + FORMATION_GAP).sum();
+          // Neighbours are spaced by arc length, but sit a chord apart, so
+          // grow the ring until the widest step's chord is as long as its arc
+          let max_rad = units.iter().map(|u| u.rad).fold(0.0, f32::max);
+          let half_step = (max_rad*2.0 + FORMATION_GAP) / circumference * PI;
+          let ring = (circumference / (2.0*PI) * half_step / half_step.sin()).max(max_rad + FORMATION_GAP);
+          let mut arc = 0.0;
+          for &ii in &order {
+            let r = units[ii].rad + FORMATION_GAP*0.5;
+            let a = -PI + (arc + r) / circumference * 2.0*PI;
+            offsets[ii] = Vec2f32(a.cos() * ring, a.sin() * ring);
+            arc += r*2.0;
+          }
+        }
+      },
+    }
+    return units.iter().zip(offsets.iter()).map(|(u, o)| {
+      (u.handle, dest + *Vec2f32::new_from_copy(&right).scale(o.0) + *Vec2f32::new_from_copy(&forward).scale(o.1))
+    }).collect();
+  }
+}
+
+/// Place units side by side, left to right in the given order, centred on
+/// the formation's centre line, at depth y.
+fn place_row(units: &[FormationUnit], order: &[usize], y: f32, offsets: &mut [Vec2f32]) {
+  let width: f32 = order.iter().map(|&ii| units[ii].rad*2.0 + FORMATION_GAP).sum::<f32>() - FORMATION_GAP;
+  let mut x = -width * 0.5;
+  for &ii in order {
+    offsets[ii] = Vec2f32(x + units[ii].rad, y);
+    x += units[ii].rad*2.0 + FORMATION_GAP;
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  const FORMATIONS: [Formation; 3] = [Formation::Line, Formation::Box, Formation::Circle];
+
+  /// A loose clump of units of mixed sizes around (100, 100).
+  fn group(n: u32) -> Vec<FormationUnit> {
+    (0..n).map(|ii| FormationUnit {
+      handle: EHandle(ii, 0),
+      pos: Vec2f32(100.0 + (ii % 4) as f32 * 30.0, 100.0 + (ii / 4) as f32 * 30.0),
+      rad: 8.0 + (ii % 3) as f32 * 4.0,
+    }).collect()
+  }
+
+  #[test]
+  fn one_slot_per_unit() {
+    for f in &FORMATIONS {
+      for n in 0..12 {
+        let units = group(n);
+        let slots = f.slots(&units, Vec2f32(500.0, 500.0));
+        assert_eq!(slots.len(), units.len());
+        for (u, s) in units.iter().zip(slots.iter()) { assert_eq!(u.handle, s.0); }
+      }
+    }
+  }
+
+  #[test]
+  fn slots_dont_overlap() {
+    for f in &FORMATIONS {
+      let units = group(11);
+      let slots = f.slots(&units, Vec2f32(500.0, 300.0));
+      for ii in 0..units.len() {
+        for jj in ii + 1..units.len() {
+          let gap = (slots[ii].1 - slots[jj].1).len() - units[ii].rad - units[jj].rad;
+          assert!(gap >= FORMATION_GAP - 0.01, "{:?} slots {} and {} {} apart", f, ii, jj, gap);
+        }
+      }
+    }
+  }
+
+  #[test]
+  fn assignment_is_stable() {
+    for f in &FORMATIONS {
+      let units = group(9);
+      let slots = f.slots(&units, Vec2f32(500.0, 100.0));
+      // Same spots whatever order the units are given in
+      let mut reversed = units.clone();
+      reversed.reverse();
+      let mut again = f.slots(&reversed, Vec2f32(500.0, 100.0));
+      again.reverse();
+      assert_eq!(slots, again);
+    }
+    // Units on the left of the group stay on the left of the line
+    let units = vec![
+      FormationUnit { handle: EHandle(0, 0), pos: Vec2f32(0.0, 100.0), rad: 8.0 },
+      FormationUnit { handle: EHandle(1, 0), pos: Vec2f32(0.0, 0.0), rad: 8.0 },
+    ];
+    let slots = Formation::Line.slots(&units, Vec2f32(500.0, 50.0));
+    assert!(slots[0].1 .1 > slots[1].1 .1);
+  }
+
+  #[test]
+  fn nan_positions_dont_panic() {
+    let mut units = group(5);
+    units[2].pos = Vec2f32(f32::NAN, 0.0);
+    for f in &FORMATIONS { assert_eq!(f.slots(&units, Vec2f32(0.0, 0.0)).len(), 5); }
+  }
+}
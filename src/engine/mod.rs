@@ -1,15 +1,40 @@
-use std::cell::Cell;
-use input::InputHandler;
-use entity::Entity;
-use game_renderer::Renderer;
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::mem;
+use input::{Control, NUM_CONTROL_GROUPS, InputHandler, InputSource, Replay, ReplayRecorder, ReplaySettings};
+use entity::{Entity, EHandle, SplitterMan, Obstacle};
+use entity::splitter_man::{JoinGroup, QueuedMove};
+use engine::common::geometry::Rect;
+use game_renderer::{Renderer, Camera, ResizePolicy};
+use minimap::Minimap;
+use level::Level;
+use self::collision::ContactBody;
+use self::command::{Command, Order};
+use self::nav::NavCache;
+use self::formation::{Formation, FormationUnit};
+use self::steering::SteeringWeights;
 use glium;
 use glium::backend::glutin_backend::GlutinFacade;
-use entity::EHandle;
-use time;
 
 pub mod common;
+pub mod checksum;
+pub mod clock;
+pub mod collision;
+pub mod command;
+pub mod entity_list;
+pub mod formation;
+pub mod nav;
+/// Saving and loading the world. The file format is described at the top of
+/// save.rs.
+pub mod save;
+pub mod selection;
+pub mod spatial_grid;
+pub mod steering;
 
 pub use self::common::vec::Vec2f32;
+pub use self::clock::{Clock, SystemClock, ManualClock};
+pub use self::entity_list::EntityList;
+pub use self::spatial_grid::SpatialGrid;
 
 fn init_display() -> GlutinFacade {
   use glium::DisplayBuild;
@@ -19,67 +44,248 @@ fn init_display() -> GlutinFacade {
     .build_glium().unwrap()
 }
 
+/// Most simulation frames update() will run in one go.
+const MAX_TICKS_PER_UPDATE: u32 = 5;
+
+/// Zoom changes by this factor for every line the mouse wheel scrolls.
+const ZOOM_STEP: f32 = 1.1;
+
+/// The cursor has to be within this many pixels of the edge of the screen to
+/// scroll the camera.
+const EDGE_SCROLL_MARGIN: i32 = 2;
+
+/// Pressing the same group key twice within this many frames centres the
+/// camera on the group.
+const GROUP_DOUBLE_TAP_TICKS: u64 = 20;
+
 pub struct Engine {
-  pub entity_list: Vec<Cell<Entity>>,
-  pub g_renderer: Renderer,
+  pub entity_list: EntityList,
+  /// The level currently being played, if one was loaded.
+  pub level: Option<Level>,
+  /// Broadphase for finding nearby entities, rebuilt every frame. If None,
+  /// every entity is considered near every other entity.
+  pub spatial_grid: Option<SpatialGrid>,
+  /// Navigation grids for pathfinding, built when first needed and thrown
+  /// away whenever an obstacle changes.
+  nav: RefCell<NavCache>,
+  /// None when running headless.
+  pub g_renderer: Option<Renderer>,
+  pub camera: Camera,
+  /// Pixels the camera pans each frame when panning with the keys or the
+  /// edge of the screen.
+  pub pan_speed: f32,
+  /// Whether touching the edge of the screen with the cursor pans the camera.
+  /// Only on when the window has grabbed the cursor, as otherwise it leaves
+  /// the window rather than stopping at the edge.
+  pub edge_scroll: bool,
+  /// How the camera's view changes when the window is resized.
+  pub resize_policy: ResizePolicy,
+  pub minimap: Minimap,
+  /// Whether the player is dragging the camera around the minimap.
+  minimap_drag: bool,
   pub input_handler: InputHandler,
-  pub display: GlutinFacade,
+  /// How many frames a splitter man takes to split. 0 splits instantly.
+  pub split_ticks: u32,
+  /// How many frames splitter men keep trying to join before giving up, in
+  /// case something stops them all reaching each other.
+  pub max_join_ticks: u32,
+  /// Maximum number of passes the collision solver makes each frame.
+  pub solver_iterations: u32,
+  /// Move orders given to at least this many splitter men at once share a
+  /// flow field to the target, rather than each finding a path with A*.
+  pub flow_field_group_size: usize,
+  /// How moving splitter men steer around each other and obstacles.
+  pub steering: SteeringWeights,
+  /// Formation selected splitter men are moved in.
+  pub formation: Formation,
+  /// Commands to carry out at the start of the next frame.
+  pending_commands: Vec<Command>,
+  /// The last control group selected, and the frame it was selected on.
+  last_group_recall: Option<(u8, u64)>,
+  /// The splitter man last clicked on, and the frame it was clicked on.
+  last_click: Option<(EHandle, u64)>,
+  /// Every group of joining splitter men, keyed by leader. Found once a
+  /// frame, before the entities update.
+  pub join_groups: BTreeMap<EHandle, JoinGroup>,
+  /// None when running headless.
+  pub display: Option<GlutinFacade>,
+  /// Where the input handler gets its events from. This is the display when
+  /// we have one.
+  input_source: Box<dyn InputSource>,
+  /// If set, every frame's input is written to this.
+  pub recorder: Option<ReplayRecorder>,
+  /// If set, input is played back from this rather than read from the input
+  /// source. The game stops when the replay runs out.
+  pub replay: Option<Replay>,
 
-  last_update_nanos: u64,
+  /// Where update() gets the time from
+  clock: Box<dyn Clock>,
+  /// When update() was last called, None before the first call
+  last_update_nanos: Option<u64>,
   frame_delta: u64,
   /// The amount of cumulative nanos passed since last frame
   nanos_cumul: u64, 
-  /// Size that nanos_cumul needs to be to run a frame
-  nanos_per_frame: u64, 
+  /// Size that nanos_cumul needs to be to run a frame. This is the fixed
+  /// length of a simulation frame.
+  pub nanos_per_frame: u64, 
+  /// Number of simulation frames run so far
+  pub tick_count: u64,
 
   /// Flag to say whether we should render this frame. Set to true when the
   /// entity updates (to sync with frame limiting)
   should_render: bool,
-
-  last_ehandle: EHandle,
 }
 
+// Engine::new opens a window, so it's no default
+#[allow(clippy::new_without_default)]
 impl Engine {
   pub fn new() -> Engine {
     use glium::glutin::CursorState;
     let display = init_display();
-    display.get_window().unwrap().set_cursor_state(CursorState::Grab).unwrap();
-    let g_renderer = Renderer::new(&display);
+    let grabbed = display.get_window().unwrap().set_cursor_state(CursorState::Grab).is_ok();
+    let (w, h) = display.get_window().unwrap().get_inner_size().unwrap();
+    let mut engine = Engine::new_headless(w as i32, h as i32, Box::new(display.clone()));
+    engine.edge_scroll = grabbed;
+    engine.g_renderer = Some(Renderer::new(&display));
+    engine.display = Some(display);
+    return engine;
+  }
+
+  /// Create an engine without a window or GL context. The camera acts as if
+  /// the screen was `screen_w` by `screen_h` pixels, and input is read from
+  /// `input` rather than the window.
+  pub fn new_headless(screen_w: i32, screen_h: i32, input: Box<dyn InputSource>) -> Engine {
     Engine { 
-      g_renderer: g_renderer, 
-      entity_list: Vec::new(),
-      display: display,
+      g_renderer: None, 
+      camera: Camera::new(screen_w as f32, screen_h as f32, screen_w, screen_h),
+      pan_speed: 8.0,
+      edge_scroll: false,
+      resize_policy: ResizePolicy::KeepScale,
+      minimap: Minimap::new(),
+      minimap_drag: false,
+      entity_list: EntityList::new(),
+      level: None,
+      spatial_grid: Some(SpatialGrid::new(64.0)),
+      nav: RefCell::new(NavCache::new(Vec::new())),
+      display: None,
       input_handler: InputHandler::new(),
-      last_ehandle: EHandle(0),
+      split_ticks: 20,
+      max_join_ticks: 600,
+      solver_iterations: 4,
+      flow_field_group_size: 8,
+      steering: SteeringWeights::new(),
+      formation: Formation::Box,
+      pending_commands: Vec::new(),
+      last_group_recall: None,
+      last_click: None,
+      join_groups: BTreeMap::new(),
+      input_source: input,
+      recorder: None,
+      replay: None,
 
-      last_update_nanos: 0,
+      clock: Box::new(SystemClock),
+      last_update_nanos: None,
       frame_delta: 0,
       nanos_cumul: 0,
       nanos_per_frame: 16666666, // 60 FPS
+      tick_count: 0,
       should_render: false,
     }
   }
 
+  /// Replace the clock update() uses to decide when to run frames. Time is
+  /// counted from now.
+  pub fn set_clock(&mut self, mut clock: Box<dyn Clock>) {
+    self.last_update_nanos = Some(clock.now_nanos());
+    self.clock = clock;
+    self.frame_delta = 0;
+    self.nanos_cumul = 0;
+  }
+
+  /// The settings to record in a replay's header.
+  pub fn replay_settings(&self) -> ReplaySettings {
+    ReplaySettings { edge_scroll: self.edge_scroll, pan_speed: self.pan_speed, resize_policy: self.resize_policy }
+  }
+
+  /// Play input back from a replay rather than the input source, with the
+  /// settings it was recorded with.
+  pub fn play_replay(&mut self, replay: Replay) {
+    self.edge_scroll = replay.settings.edge_scroll;
+    self.pan_speed = replay.settings.pan_speed;
+    self.resize_policy = replay.settings.resize_policy;
+    self.replay = Some(replay);
+  }
+
   /// Update the counter time and delta in LibState.
   fn update_delta(&mut self) {
-    let now = time::precise_time_ns();
-    if self.last_update_nanos != 0 {
-      self.frame_delta = now - self.last_update_nanos;
+    let now = self.clock.now_nanos();
+    if let Some(last) = self.last_update_nanos {
+      self.frame_delta = now - last;
     }
     self.nanos_cumul += self.frame_delta;
-    self.last_update_nanos = now;
+    self.last_update_nanos = Some(now);
   }
 
-  /// Updates the engine. Returns true if the game should stop.
+  /// Updates the engine, running as many fixed length frames as the clock
+  /// says are due. Returns true if the game should stop.
   pub fn update(&mut self) -> bool {
     self.update_delta();
-    if self.nanos_cumul < self.nanos_per_frame { return false; }
-    self.nanos_cumul -= self.nanos_per_frame;
-    self.should_render = true;
+    let mut ticks = 0;
+    while self.nanos_cumul >= self.nanos_per_frame {
+      self.nanos_cumul -= self.nanos_per_frame;
+      self.should_render = true;
+      if self.tick() { return true; }
+      ticks += 1;
+      // If we're this far behind, drop the time rather than trying to catch
+      // up forever
+      if ticks >= MAX_TICKS_PER_UPDATE {
+        self.nanos_cumul = 0;
+        break;
+      }
+    }
+    return false;
+  }
 
-    if self.input_handler.check_input(&self.display) {
+  /// Run a single frame of the simulation (input and entity updates),
+  /// regardless of how much time has passed. Returns true if the game should
+  /// stop. The simulation never reads the clock, so the same input on the
+  /// same frames always gives exactly the same entity state.
+  pub fn tick(&mut self) -> bool {
+    if let Some(ref mut replay) = self.replay {
+      // Still drain the window's events so it stays responsive and can be
+      // closed, but otherwise ignore them.
+      for e in self.input_source.poll() {
+        if let glium::glutin::Event::Closed = e { return true; }
+      }
+      match replay.next_record() {
+        Some(r) => self.input_handler.apply_record(r),
+        None => return true,
+      }
+    }
+    else if self.input_handler.check_input(&mut *self.input_source) {
       return true;
     }
+    self.tick_count += 1;
+    if let Some(ref mut recorder) = self.recorder {
+      recorder.record(&self.input_handler).unwrap();
+    }
+    if self.input_handler.inputs.get(&Control::Formation).unwrap().just_down {
+      self.formation = self.formation.next();
+    }
+    self.check_minimap();
+    self.update_camera();
+    self.update_selection();
+    self.check_control_groups();
+    // Orders issued since the last frame go before the player's
+    let mut commands = mem::take(&mut self.pending_commands);
+    let selected = self.selected();
+    let dest = self.cursor_world();
+    commands.append(&mut self.input_handler.commands(&selected, dest));
+    for c in commands { self.apply_command(c); }
+    if let Some(ref mut grid) = self.spatial_grid {
+      grid.rebuild(&self.entity_list);
+    }
+    self.join_groups = SplitterMan::find_join_groups(&self.entity_list);
     let mut pos_updates = Vec::new();
     let mut ents = Vec::new(); // Entities to append to the entity list at the end of the loop
     let mut to_remove = Vec::new();
@@ -87,55 +293,758 @@ impl Engine {
       let mut e_copy = e.get();
       let (remove, mut _ents, new_pos) = e_copy.update(&*self);
       e.set(e_copy);
-      if _ents.is_some() {
-        ents.append(&mut _ents.unwrap());
+      if let Some(ref mut new_ents) = _ents {
+        ents.append(new_ents);
       }
-      if remove { to_remove.push(e_copy.get_entity_handle().unwrap()); }
-      pos_updates.push((e_copy.get_entity_handle(), new_pos));
+      let e_h = e_copy.get_entity_handle().unwrap();
+      if remove { to_remove.push(e_h); }
+      pos_updates.push((e_h, new_pos));
     }
     for e in ents { self.add_entity(e); }
-    for e_h in to_remove {
-      let mut ix = 100000000;
-      for (ii, mut e) in self.entity_list.iter_mut().enumerate() {
-        let e = e.get_mut();
-        if e_h.0 == e.get_entity_handle().unwrap().0 {
-          ix = ii;
-          break;
+    for e_h in to_remove { self.entity_list.remove(e_h); }
+    // Update positions
+    for (e_h, p) in pos_updates {
+      if let Some(e) = self.entity_list.get_mut(e_h) { e.set_pos(p); }
+    }
+    self.resolve_collisions();
+    self.check_nav();
+    return false;
+  }
+
+  /// Rebuild the navigation grids if any obstacle has changed this frame, and
+  /// make every splitter man find a new path.
+  fn check_nav(&mut self) {
+    let obstacles = self.obstacles();
+    if !self.nav.get_mut().set_obstacles(obstacles) { return; }
+    for e in &self.entity_list {
+      if let Entity::SplitterMan(mut s) = e.get() {
+        s.path = None;
+        e.set(Entity::SplitterMan(s));
+      }
+    }
+  }
+
+  /// Throw away the navigation grids after the entity list has been
+  /// replaced. Paths splitter men are already following are kept.
+  fn reset_nav(&mut self) {
+    self.nav = RefCell::new(NavCache::new(self.obstacles()));
+  }
+
+  /// Every obstacle, in handle order.
+  fn obstacles(&self) -> Vec<Obstacle> {
+    let mut obstacles = Vec::new();
+    for e in &self.entity_list {
+      if let Entity::Obstacle(o) = e.get() { obstacles.push(o); }
+    }
+    return obstacles;
+  }
+
+  /// Find a path for a splitter man of the given radius around the level's
+  /// obstacles. If the destination can't be reached, the path leads to the
+  /// closest place that can be.
+  /// # Returns
+  /// The waypoints to follow, the last being the end of the path. None if
+  /// there's no level loaded, or `from` is outside the level.
+  pub fn find_path(&self, from: Vec2f32, to: Vec2f32, rad: f32) -> Option<Vec<Vec2f32>> {
+    let bounds = match self.level { Some(ref l) => l.bounds, None => return None };
+    return self.nav.borrow_mut().grid(&bounds, rad).find_path_towards(from, to);
+  }
+
+  /// Same as find_path, but follows a flow field to the group's destination,
+  /// which is cached and shared with every other splitter man heading there,
+  /// until `to` is in sight. Much cheaper than find_path for big groups.
+  pub fn find_group_path(&self, from: Vec2f32, to: Vec2f32, group_dest: Vec2f32, rad: f32) -> Option<Vec<Vec2f32>> {
+    let bounds = match self.level { Some(ref l) => l.bounds, None => return None };
+    return self.nav.borrow_mut().find_group_path(&bounds, rad, from, to, group_dest);
+  }
+
+  /// Move the camera to wherever the player clicks or drags on the minimap.
+  /// Clicks on the minimap don't select anything.
+  fn check_minimap(&mut self) {
+    let bounds = match self.level { Some(ref l) => l.bounds, None => return };
+    let screen = self.camera.screen_size();
+    let m = self.input_handler.mouse_pos;
+    let on_map = self.minimap.panel_to_world(&bounds, screen, Vec2f32(m.0 as f32, m.1 as f32));
+    let select = self.input_handler.inputs.get(&Control::Select).unwrap();
+    let (down, just_down) = (select.down, select.just_down);
+    if just_down { self.minimap_drag = on_map.is_some(); }
+    if !self.minimap_drag { return; }
+    if let Some(p) = on_map { self.camera.centre_on(p); }
+    self.input_handler.curr_box = None;
+    self.input_handler.curr_lasso = None;
+    self.input_handler.selection = None;
+    self.input_handler.lasso = None;
+    if !down { self.minimap_drag = false; }
+  }
+
+  /// The world point under the cursor, looking through the minimap if the
+  /// cursor is over it.
+  pub fn cursor_world(&self) -> Vec2f32 {
+    let m = self.input_handler.mouse_pos;
+    if let Some(ref l) = self.level {
+      let on_map = self.minimap.panel_to_world(&l.bounds, self.camera.screen_size(), Vec2f32(m.0 as f32, m.1 as f32));
+      if let Some(p) = on_map { return p; }
+    }
+    return self.camera.screen_to_world(m.0, m.1);
+  }
+
+  /// Resize, pan and zoom the camera from this frame's input, keeping it
+  /// inside the level.
+  fn update_camera(&mut self) {
+    if let Some((w, h)) = self.input_handler.resized {
+      self.camera.resize(w, h, self.resize_policy);
+    }
+    let inputs = &self.input_handler.inputs;
+    let down = |c| inputs.get(&c).unwrap().down;
+    let mut dir = Vec2f32(0.0, 0.0);
+    if down(Control::PanLeft) { dir.0 -= 1.0; }
+    if down(Control::PanRight) { dir.0 += 1.0; }
+    if down(Control::PanUp) { dir.1 -= 1.0; }
+    if down(Control::PanDown) { dir.1 += 1.0; }
+    let m = self.input_handler.mouse_pos;
+    // The cursor is at a made up (0, 0) until it first moves, which would
+    // scroll up and left
+    if self.edge_scroll && self.input_handler.mouse_moved {
+      let (w, h) = self.camera.screen_size();
+      if m.0 <= EDGE_SCROLL_MARGIN { dir.0 -= 1.0; }
+      if m.0 >= w - 1 - EDGE_SCROLL_MARGIN { dir.0 += 1.0; }
+      if m.1 <= EDGE_SCROLL_MARGIN { dir.1 -= 1.0; }
+      if m.1 >= h - 1 - EDGE_SCROLL_MARGIN { dir.1 += 1.0; }
+    }
+    // Keys and the edge of the screen don't add up
+    let mut dir = Vec2f32(dir.0.clamp(-1.0, 1.0), dir.1.clamp(-1.0, 1.0));
+    if dir.len2() > 0.0 {
+      let speed = self.pan_speed * self.camera.zoom();
+      self.camera.pan(*dir.nor().scale(speed));
+    }
+    // Scrolling away from the player zooms in
+    if self.input_handler.wheel != 0.0 {
+      self.camera.zoom_about(m.0, m.1, ZOOM_STEP.powf(-self.input_handler.wheel));
+    }
+    if let Some(ref l) = self.level { self.camera.clamp_to(&l.bounds); }
+  }
+
+  /// Set or select any control groups whose keys were just pressed.
+  fn check_control_groups(&mut self) {
+    let inputs = &self.input_handler.inputs;
+    let set = inputs.get(&Control::SetGroup).unwrap().down;
+    let pressed: Vec<u8> = (0..NUM_CONTROL_GROUPS).filter(|n| {
+      inputs.get(&Control::Group(*n)).unwrap().just_down
+    }).collect();
+    for n in pressed {
+      if set { self.set_control_group(n); }
+      else { self.select_control_group(n); }
+    }
+  }
+
+  /// Put every selected splitter man in control group `n`, and take
+  /// everyone else out of it.
+  pub fn set_control_group(&mut self, n: u8) {
+    for e in &self.entity_list {
+      if let Entity::SplitterMan(mut s) = e.get() {
+        if s.selected { s.groups |= 1 << n; }
+        else { s.groups &= !(1 << n); }
+        e.set(Entity::SplitterMan(s));
+      }
+    }
+  }
+
+  /// Handles of every splitter man in control group `n`, in handle order.
+  pub fn control_group(&self, n: u8) -> Vec<EHandle> {
+    let mut handles = Vec::new();
+    for e in &self.entity_list {
+      if let Entity::SplitterMan(s) = e.get() {
+        if s.groups & (1 << n) != 0 { handles.push(s.id.unwrap()); }
+      }
+    }
+    return handles;
+  }
+
+  /// Select just the splitter men in control group `n`. Selecting the same
+  /// group twice in quick succession centres the camera on it. Does nothing
+  /// if the group is empty.
+  pub fn select_control_group(&mut self, n: u8) {
+    let group = self.control_group(n);
+    if group.is_empty() { return; }
+    let mut centre = Vec2f32(0.0, 0.0);
+    for e in &self.entity_list {
+      if let Entity::SplitterMan(mut s) = e.get() {
+        s.selected = s.groups & (1 << n) != 0;
+        if s.selected { centre += s.body.pos; }
+        e.set(Entity::SplitterMan(s));
+      }
+    }
+    if let Some((last, tick)) = self.last_group_recall {
+      if last == n && tick <= self.tick_count && self.tick_count - tick <= GROUP_DOUBLE_TAP_TICKS {
+        self.camera.centre_on(*centre.scale(1.0 / group.len() as f32));
+      }
+    }
+    self.last_group_recall = Some((n, self.tick_count));
+  }
+
+  /// Queue a command to be carried out at the start of the next frame,
+  /// before the player's input.
+  pub fn issue(&mut self, c: Command) { self.pending_commands.push(c); }
+
+  /// Handles of every selected splitter man, in handle order.
+  pub fn selected(&self) -> Vec<EHandle> {
+    let mut handles = Vec::new();
+    for e in &self.entity_list {
+      if let Entity::SplitterMan(s) = e.get() {
+        if s.selected { handles.push(s.id.unwrap()); }
+      }
+    }
+    return handles;
+  }
+
+  /// Carry out a command straight away. Splitter men which are busy
+  /// splitting or joining ignore it.
+  pub fn apply_command(&mut self, c: Command) {
+    // Work through the units in handle order, so the same command always
+    // gives the same result
+    let mut handles = c.units;
+    handles.sort();
+    handles.dedup();
+    let mut units = Vec::new();
+    for h in handles {
+      if let Some(Entity::SplitterMan(s)) = self.entity_list.get(h) {
+        if s.can_take_orders() { units.push(s); }
+      }
+    }
+    if units.is_empty() { return; }
+    let formation_units: Vec<FormationUnit> = units.iter().map(|s| {
+      FormationUnit { handle: s.id.unwrap(), pos: s.body.pos, rad: s.body.rad }
+    }).collect();
+    // Big groups share a flow field to where they were sent
+    let shared = units.len() >= self.flow_field_group_size;
+
+    let mut to_add = Vec::new();
+    let mut to_remove = Vec::new();
+    match c.order {
+      Order::Move { dest, queue } => {
+        let slots = self.formation.slots(&formation_units, dest);
+        let group_target = if shared { Some(dest) } else { None };
+        for (s, slot) in units.iter_mut().zip(slots.iter()) {
+          s.order_move(slot.1, group_target, queue);
+        }
+      },
+      Order::Split => {
+        for s in &mut units {
+          if let Some((remove, mut children)) = s.split(self.split_ticks) {
+            if remove { to_remove.push(s.id.unwrap()); }
+            to_add.append(&mut children);
+          }
+        }
+      },
+      Order::Join => {
+        // Units are in handle order, so the first is the lowest
+        let leader = units[0].id.unwrap();
+        for s in &mut units { s.join(leader); }
+      },
+      Order::Stop => for s in &mut units { s.order_stop(); },
+      Order::Hold => for s in &mut units { s.hold(); },
+      Order::Patrol(mut points) => {
+        // Patrol between where the group is and the one point
+        if points.len() == 1 {
+          let mut centre = Vec2f32(0.0, 0.0);
+          for u in &formation_units { centre += u.pos; }
+          points.insert(0, *centre.scale(1.0 / formation_units.len() as f32));
         }
+        let slots: Vec<Vec<(EHandle, Vec2f32)>> = points.iter().map(|p| {
+          self.formation.slots(&formation_units, *p)
+        }).collect();
+        for (ix, s) in units.iter_mut().enumerate() {
+          let moves: Vec<QueuedMove> = slots.iter().zip(points.iter()).map(|(sl, p)| {
+            QueuedMove { target: sl[ix].1, group_target: if shared { Some(*p) } else { None } }
+          }).collect();
+          s.patrol(&moves);
+        }
+      },
+    }
+    for s in units {
+      if let Some(e) = self.entity_list.get_mut(s.id.unwrap()) { *e = Entity::SplitterMan(s); }
+    }
+    for e in to_add { self.add_entity(e); }
+    for h in to_remove { self.entity_list.remove(h); }
+  }
+
+  /// Push apart any overlapping entities, once they've all moved this frame.
+  fn resolve_collisions(&mut self) {
+    if let Some(ref mut grid) = self.spatial_grid {
+      grid.rebuild(&self.entity_list);
+    }
+    // The entity list iterates in handle order, so bodies and shapes are
+    // sorted by handle
+    let mut bodies = Vec::new();
+    // Whether each body passes through the other bodies. Obstacles still
+    // stop it.
+    let mut ghosts = Vec::new();
+    let mut obstacles = Vec::new();
+    for e in &self.entity_list {
+      let e = e.get();
+      match e {
+        Entity::Obstacle(o) => { obstacles.push(o); continue; },
+        Entity::SplitterMan(_) => (),
+        _ => continue,
       }
-      self.entity_list.remove(ix);
+      let mass = e.get_mass();
+      let body = e.get_body();
+      bodies.push(ContactBody { 
+        handle: e.get_entity_handle().unwrap(), 
+        pos: body.pos, 
+        rad: body.rad, 
+        inv_mass: mass.map_or(0.0, |m| 1.0 / m),
+      });
+      ghosts.push(mass.is_none());
     }
-    // Update positions
-    for (e_h, p) in pos_updates {
-      for e in &mut self.entity_list {
-        let e = e.get_mut();
-        if e_h.unwrap().0 == e.get_entity_handle().unwrap().0 {
-          e.set_pos(p);
+    let mut pairs = Vec::new();
+    let mut shape_pairs = Vec::new();
+    for (ii, b) in bodies.iter().enumerate() {
+      for h in self.neighbours(b.pos, b.rad) {
+        if let Ok(jj) = obstacles.binary_search_by_key(&h, |o| o.id.unwrap()) {
+          if obstacles[jj].blocks(b.rad) { shape_pairs.push((ii, jj)); }
+          continue;
+        }
+        if ghosts[ii] || h <= b.handle { continue; }
+        if let Ok(jj) = bodies.binary_search_by_key(&h, |b| b.handle) {
+          if !ghosts[jj] { pairs.push((ii, jj)); }
         }
       }
     }
-    return false;
+    let shapes: Vec<_> = obstacles.iter().map(|o| o.shape).collect();
+    collision::solve(&mut bodies, &pairs, &shapes, &shape_pairs, self.solver_iterations);
+    for b in bodies {
+      self.entity_list.get_mut(b.handle).unwrap().set_pos(b.pos);
+    }
   }
 
   pub fn render(&mut self) {
     use glium::Surface;
     if !self.should_render { return; }
     self.should_render = false;
-    self.g_renderer.update_proj_mat();
-    let mut target = self.display.draw();
+    if self.display.is_none() || self.g_renderer.is_none() { return; }
+    self.g_renderer.as_mut().unwrap().update_proj_mat(&self.camera);
+    let mut target = self.display.as_ref().unwrap().draw();
     target.clear_color(0.1, 0.1, 0.1, 1.0);
-    self.g_renderer.render(&mut target, self);
+    self.g_renderer.as_ref().unwrap().render(&mut target, self);
     target.finish().unwrap();
   }
 
-  fn gen_entity_id(&mut self) -> EHandle {
-    self.last_ehandle.0 += 1;
-    return EHandle(self.last_ehandle.0);
+  /// Find the entities which might overlap a circle. This is only a
+  /// broadphase, so some of the returned entities may not actually overlap.
+  /// # Returns
+  /// The handles of the entities, in handle order.
+  pub fn neighbours(&self, pos: Vec2f32, rad: f32) -> Vec<EHandle> {
+    match self.spatial_grid {
+      Some(ref grid) => grid.query(pos, rad),
+      None => self.entity_list.iter().map(|e| e.get().get_entity_handle().unwrap()).collect(),
+    }
+  }
+
+  /// Start a level, replacing every entity with the level's obstacles and
+  /// spawns.
+  pub fn load_level(&mut self, level: Level) {
+    self.entity_list = EntityList::new();
+    for o in &level.obstacles {
+      self.add_entity(Entity::Obstacle(*o));
+    }
+    for p in &level.plates {
+      self.add_entity(Entity::PressurePlate(*p));
+    }
+    for s in &level.spawns {
+      self.add_entity(Entity::SplitterMan(SplitterMan::new(s.pos.0, s.pos.1, s.size)));
+    }
+    self.level = Some(level);
+    self.reset_nav();
+  }
+
+  /// Total load of the splitter men whose centres are inside an area. See
+  /// SplitterMan::load.
+  pub fn load_on(&self, area: &Rect) -> u32 {
+    let mut load = 0;
+    let c = area.centre();
+    for h in self.neighbours(c, (area.max() - c).len()) {
+      if let Some(Entity::SplitterMan(s)) = self.entity_list.get(h) {
+        if area.contains(s.body.pos) { load += s.load(); }
+      }
+    }
+    return load;
+  }
+
+  /// True if there is at least one pressure plate on the channel, and every
+  /// plate on the channel is pressed.
+  pub fn channel_active(&self, channel: u32) -> bool {
+    let mut any = false;
+    for e in &self.entity_list {
+      if let Entity::PressurePlate(p) = e.get() {
+        if p.channel != channel { continue; }
+        if self.load_on(&p.area) < p.threshold { return false; }
+        any = true;
+      }
+    }
+    return any;
+  }
+
+  /// True if a level with goals is loaded, and every goal has a splitter man
+  /// standing in it.
+  pub fn goals_complete(&self) -> bool {
+    let level = match self.level { Some(ref l) => l, None => return false };
+    if level.goals.is_empty() { return false; }
+    return level.goals.iter().all(|g| {
+      self.entity_list.iter().any(|e| match e.get() {
+        Entity::SplitterMan(s) => g.contains(s.body.pos),
+        _ => false,
+      })
+    });
   }
 
-  pub fn add_entity(&mut self, mut e: Entity) {
-    e.set_entity_handle(self.gen_entity_id());
-    self.entity_list.push(Cell::new(e));
+  pub fn add_entity(&mut self, e: Entity) -> EHandle {
+    self.entity_list.insert(e)
   }
 }
 
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use glium::glutin::Event;
+  use input::QueuedInput;
+  use engine::command::{Command, Order};
+  use entity::ObstacleKind;
+  use entity::splitter_man::State;
+
+  fn engine(level: &str) -> Engine {
+    let mut e = Engine::new_headless(800, 600, Box::new(QueuedInput::new()));
+    e.load_level(Level::parse(level).unwrap());
+    return e;
+  }
+
+  /// Every splitter man, in handle order.
+  fn units(e: &Engine) -> Vec<SplitterMan> {
+    let mut units = Vec::new();
+    for ent in &e.entity_list {
+      if let Entity::SplitterMan(s) = ent.get() { units.push(s); }
+    }
+    return units;
+  }
+
+  fn handles(e: &Engine) -> Vec<EHandle> { units(e).iter().map(|s| s.id.unwrap()).collect() }
+
+  #[test]
+  fn ticks_headless() {
+    let mut e = Engine::new_headless(800, 600, Box::new(QueuedInput::new()));
+    e.load_level(Level::parse("bounds 0 0 800 600\nspawn 400 300 4\n").unwrap());
+    for _ in 0..10 { assert!(!e.tick()); }
+    assert_eq!(e.tick_count, 10);
+    assert_eq!(e.entity_list.len(), 1);
+  }
+
+  #[test]
+  fn closing_stops_ticking() {
+    let input = QueuedInput::new();
+    let mut e = Engine::new_headless(800, 600, Box::new(input.clone()));
+    assert!(!e.tick());
+    input.push(Event::Closed);
+    assert!(e.tick());
+  }
+
+  #[test]
+  fn join_orders_make_separate_groups() {
+    let mut e = engine("bounds 0 0 800 600\nspawn 100 300 1\nspawn 140 300 1\nspawn 600 300 1\nspawn 640 300 1\n");
+    let h = handles(&e);
+    e.issue(Command::new(vec![h[0], h[1]], Order::Join));
+    e.issue(Command::new(vec![h[2], h[3]], Order::Join));
+    for _ in 0..100 { e.tick(); }
+    let mut joined = units(&e);
+    joined.sort_by(|a, b| a.body.pos.0.total_cmp(&b.body.pos.0));
+    assert_eq!(joined.len(), 2);
+    assert!(joined.iter().all(|s| s.size == 2));
+    assert!((joined[0].body.pos - Vec2f32(120.0, 300.0)).len() < 1.0);
+    assert!((joined[1].body.pos - Vec2f32(620.0, 300.0)).len() < 1.0);
+  }
+
+  #[test]
+  fn obstacles_push_out_every_unit() {
+    let mut e = engine("bounds 0 0 800 600\nbox 300 200 100 100\nspawn 100 100 1\n");
+    let mut joining = SplitterMan::new(310.0, 250.0, 4);
+    joining.join(EHandle(0, 0));
+    let mut splitting = SplitterMan::new(310.0, 220.0, 4);
+    splitting.split(20);
+    let handles: Vec<EHandle> = vec![SplitterMan::new(310.0, 280.0, 4), joining, splitting].into_iter()
+      .map(|s| e.add_entity(Entity::SplitterMan(s))).collect();
+    e.resolve_collisions();
+    for h in handles {
+      let body = e.entity_list.get(h).unwrap().get_body();
+      // Out the nearest side
+      assert!(body.pos.0 + body.rad <= 300.01, "{:?} still in the box", body);
+    }
+  }
+
+  #[test]
+  fn gaps_only_stop_big_units() {
+    let mut e = engine("bounds 0 0 800 600\ngap 300 200 100 100 10\nspawn 100 100 1\n");
+    // Radius 8 and 16
+    let small = e.add_entity(Entity::SplitterMan(SplitterMan::new(310.0, 220.0, 1)));
+    let big = e.add_entity(Entity::SplitterMan(SplitterMan::new(310.0, 280.0, 4)));
+    e.resolve_collisions();
+    assert_eq!(e.entity_list.get(small).unwrap().get_body().pos, Vec2f32(310.0, 220.0));
+    assert!(e.entity_list.get(big).unwrap().get_body().pos.0 <= 300.0 - 16.0 + 0.01);
+  }
+
+  #[test]
+  fn splitting_units_weigh_their_size_once() {
+    let mut e = engine("bounds 0 0 800 600\nplate 0 0 400 600 4 1\nspawn 100 300 4\nspawn 600 300 8\n");
+    let plate = Rect::new(0.0, 0.0, 400.0, 600.0);
+    e.tick();
+    assert_eq!(e.load_on(&plate), 4);
+    let h = handles(&e);
+    e.issue(Command::new(h, Order::Split));
+    for _ in 0..e.split_ticks + 5 {
+      e.tick();
+      assert_eq!(e.load_on(&plate), 4);
+    }
+    assert_eq!(units(&e).len(), 4);
+  }
+
+  #[test]
+  fn plates_open_doors() {
+    let mut e = engine("bounds 0 0 800 600\nplate 0 0 100 100 3 1\nplate 0 500 100 100 1 1\n\
+                        door 400 0 20 600 1\nspawn 50 50 2\nspawn 50 550 1\nspawn 600 300 1\n");
+    let door_open = |e: &Engine| e.entity_list.iter().any(|o| match o.get() {
+      Entity::Obstacle(o) => o.kind == ObstacleKind::Door { channel: 1, open: true },
+      _ => false,
+    });
+    let h = handles(&e);
+    // Not enough weight on the first plate
+    e.tick();
+    assert!(!door_open(&e));
+    // Every plate on the channel needs pressing
+    e.entity_list.get_mut(h[2]).unwrap().set_pos(Vec2f32(50.0, 40.0));
+    e.entity_list.get_mut(h[1]).unwrap().set_pos(Vec2f32(200.0, 550.0));
+    e.tick();
+    assert!(!door_open(&e));
+    e.entity_list.get_mut(h[1]).unwrap().set_pos(Vec2f32(50.0, 550.0));
+    e.tick();
+    assert!(door_open(&e));
+    // Shuts again once someone steps off
+    e.entity_list.get_mut(h[0]).unwrap().set_pos(Vec2f32(200.0, 50.0));
+    e.tick();
+    assert!(!door_open(&e));
+  }
+
+  #[test]
+  fn stop_forgets_orders() {
+    let mut e = engine("bounds 0 0 800 600\nspawn 100 300 1\n");
+    let h = handles(&e);
+    e.issue(Command::new(h.clone(), Order::Move { dest: Vec2f32(600.0, 300.0), queue: false }));
+    e.issue(Command::new(h.clone(), Order::Move { dest: Vec2f32(600.0, 100.0), queue: true }));
+    for _ in 0..10 { e.tick(); }
+    e.issue(Command::new(h, Order::Stop));
+    e.tick();
+    let s = units(&e)[0];
+    assert!(s.target.is_none() && s.queue.is_empty());
+    assert!(matches!(s.state, State::Idle(_)));
+    let pos = s.body.pos;
+    for _ in 0..20 { e.tick(); }
+    assert!((units(&e)[0].body.pos - pos).len() < 1.0);
+  }
+
+  #[test]
+  fn holding_units_ignore_shoves() {
+    let mut e = engine("bounds 0 0 800 600\nspawn 100 300 4\nspawn 110 300 8\n");
+    let h = handles(&e);
+    e.issue(Command::new(vec![h[0]], Order::Hold));
+    e.tick();
+    let s = units(&e);
+    // Even the bigger one has to go round
+    assert_eq!(s[0].body.pos, Vec2f32(100.0, 300.0));
+    assert!((s[1].body.pos - s[0].body.pos).len() >= s[0].body.rad + s[1].body.rad - 0.01);
+  }
+
+  #[test]
+  fn doors_push_out_holding_units() {
+    let mut e = engine("bounds 0 0 800 600\nplate 0 0 100 100 1 1\ndoor 400 0 20 600 1\n\
+                        spawn 50 50 1\nspawn 200 300 1\n");
+    let h = handles(&e);
+    e.issue(Command::new(vec![h[1]], Order::Hold));
+    e.tick();
+    // Holding in the open doorway
+    e.entity_list.get_mut(h[1]).unwrap().set_pos(Vec2f32(410.0, 300.0));
+    e.tick();
+    assert_eq!(units(&e)[1].body.pos, Vec2f32(410.0, 300.0));
+    // Step off the plate so the door shuts on him
+    e.entity_list.get_mut(h[0]).unwrap().set_pos(Vec2f32(200.0, 50.0));
+    e.tick();
+    e.tick();
+    let s = units(&e)[1];
+    assert!(matches!(s.state, State::Holding(_)));
+    assert!(s.body.pos.0 + s.body.rad <= 400.01 || s.body.pos.0 - s.body.rad >= 419.99, "{:?} in the door", s.body);
+  }
+
+  #[test]
+  fn patrols_loop() {
+    let mut e = engine("bounds 0 0 800 600\nspawn 100 300 1\n");
+    let h = handles(&e);
+    e.issue(Command::new(h, Order::Patrol(vec![Vec2f32(300.0, 300.0)])));
+    let (mut at_start, mut at_end, mut was_at) = (0, 0, 0.0);
+    for _ in 0..2000 {
+      e.tick();
+      let s = units(&e)[0];
+      assert!(matches!(s.state, State::Patrolling(_)));
+      assert_eq!(s.queue.len(), 1);
+      let x = s.body.pos.0;
+      if x < 101.0 && was_at != 100.0 { at_start += 1; was_at = 100.0; }
+      if x > 299.0 && was_at != 300.0 { at_end += 1; was_at = 300.0; }
+    }
+    assert!(at_start >= 2 && at_end >= 2, "only went back and forth {}/{} times", at_start, at_end);
+  }
+
+  #[test]
+  fn splits_and_joins_keep_groups() {
+    let mut e = engine("bounds 0 0 800 600\nspawn 100 300 2\nspawn 600 300 1\n");
+    let h = handles(&e);
+    if let Some(Entity::SplitterMan(s)) = e.entity_list.get_mut(h[0]) { s.selected = true; }
+    e.set_control_group(3);
+    e.issue(Command::new(vec![h[0]], Order::Split));
+    for _ in 0..e.split_ticks + 5 { e.tick(); }
+    let children = e.control_group(3);
+    assert_eq!(children.len(), 2);
+    assert!(!children.contains(&h[1]));
+    e.issue(Command::new(children, Order::Join));
+    for _ in 0..200 { e.tick(); }
+    let joined = e.control_group(3);
+    assert_eq!(joined.len(), 1);
+    assert_eq!(units(&e).len(), 2);
+    e.select_control_group(3);
+    assert_eq!(e.selected(), joined);
+  }
+
+  #[test]
+  fn double_tapping_a_group_centres_on_it() {
+    let mut e = engine("bounds 0 0 2000 2000\nspawn 1500 1500 1\n");
+    if let Some(Entity::SplitterMan(s)) = e.entity_list.get_mut(handles(&e)[0]) { s.selected = true; }
+    e.set_control_group(2);
+    let start = Vec2f32(400.0, 300.0);
+    e.camera.centre_on(start);
+    e.select_control_group(2);
+    assert_eq!(e.camera.pos(), start);
+    e.tick_count += GROUP_DOUBLE_TAP_TICKS;
+    e.select_control_group(2);
+    assert_eq!(e.camera.pos(), Vec2f32(1500.0, 1500.0));
+
+    // One tap after loading an earlier save isn't a double tap
+    let mut saved = Vec::new();
+    e.write_save(&mut saved).unwrap();
+    e.camera.centre_on(start);
+    e.tick_count += 100;
+    e.select_control_group(2);
+    e.read_save(&saved[..]).unwrap();
+    e.select_control_group(2);
+    assert_eq!(e.camera.pos(), start);
+    // Even if the tap wasn't forgotten
+    e.last_group_recall = Some((2, 1000));
+    e.select_control_group(2);
+    assert_eq!(e.camera.pos(), start);
+  }
+
+  #[test]
+  fn edge_scroll_waits_for_the_mouse() {
+    let input = QueuedInput::new();
+    let mut e = Engine::new_headless(800, 600, Box::new(input.clone()));
+    e.load_level(Level::parse("bounds 0 0 2000 2000\nspawn 100 100 1\n").unwrap());
+    e.camera.centre_on(Vec2f32(1000.0, 1000.0));
+    // Off without a window
+    input.push(Event::MouseMoved(0, 300));
+    e.tick();
+    assert_eq!(e.camera.pos(), Vec2f32(1000.0, 1000.0));
+
+    let input = QueuedInput::new();
+    let mut e = Engine::new_headless(800, 600, Box::new(input.clone()));
+    e.load_level(Level::parse("bounds 0 0 2000 2000\nspawn 100 100 1\n").unwrap());
+    e.camera.centre_on(Vec2f32(1000.0, 1000.0));
+    e.edge_scroll = true;
+    e.tick();
+    assert_eq!(e.camera.pos(), Vec2f32(1000.0, 1000.0));
+    input.push(Event::MouseMoved(0, 300));
+    e.tick();
+    assert!(e.camera.pos().0 < 1000.0);
+    assert_eq!(e.camera.pos().1, 1000.0);
+  }
+
+  #[test]
+  fn joiners_find_their_way_round_walls() {
+    // A wall between them, with a way round underneath
+    let mut e = engine("bounds 0 0 800 600\nbox 300 0 20 450\nspawn 200 300 1\nspawn 600 300 1\n");
+    e.issue(Command::new(handles(&e), Order::Join));
+    for _ in 0..e.max_join_ticks { e.tick(); }
+    let joined = units(&e);
+    assert_eq!(joined.len(), 1);
+    assert_eq!(joined[0].size, 2);
+  }
+
+  #[test]
+  fn joiners_give_up_and_take_orders() {
+    // Walled off from each other
+    let mut e = engine("bounds 0 0 800 600\nbox 350 0 100 600\nspawn 200 300 1\nspawn 600 300 1\n");
+    e.max_join_ticks = 200;
+    let h = handles(&e);
+    e.issue(Command::new(h.clone(), Order::Join));
+    for _ in 0..e.max_join_ticks + 2 { e.tick(); }
+    let s = units(&e);
+    assert_eq!(s.len(), 2);
+    assert!(s.iter().all(|s| matches!(s.state, State::Idle(_)) && s.target.is_none()));
+    e.issue(Command::new(vec![h[0]], Order::Move { dest: Vec2f32(100.0, 100.0), queue: false }));
+    for _ in 0..200 { e.tick(); }
+    assert!((units(&e)[0].body.pos - Vec2f32(100.0, 100.0)).len() < 1.0);
+
+    // Or can be told to do something else straight away
+    e.issue(Command::new(h.clone(), Order::Join));
+    e.tick();
+    assert!(units(&e).iter().all(|s| matches!(s.state, State::Joining(_))));
+    e.issue(Command::new(h, Order::Stop));
+    e.tick();
+    assert!(units(&e).iter().all(|s| matches!(s.state, State::Idle(_))));
+  }
+
+  #[test]
+  fn splits_grow_apart_then_take_orders() {
+    let mut e = engine("bounds 0 0 800 600\nspawn 400 300 4\n");
+    e.select_all();
+    let parent = handles(&e)[0];
+    e.issue(Command::new(vec![parent], Order::Split));
+    e.tick();
+    let children: Vec<EHandle> = handles(&e).into_iter().filter(|h| *h != parent).collect();
+    assert_eq!(children.len(), 2);
+    let child_rad = SplitterMan::calc_size(2);
+    let (mut last_rad, mut last_gap) = (0.0, 0.0);
+    for tick in 1..e.split_ticks {
+      let s = units(&e);
+      assert_eq!(s.len(), 3);
+      assert!(s.iter().all(|s| matches!(s.state, State::Splitting(_))));
+      let (a, b) = (s[1].body, s[2].body);
+      assert!(a.rad > last_rad && a.rad < child_rad);
+      assert!((b.pos - a.pos).len() > last_gap);
+      last_rad = a.rad;
+      last_gap = (b.pos - a.pos).len();
+      // Orders part way through are ignored
+      if tick == e.split_ticks / 2 {
+        e.issue(Command::new(handles(&e), Order::Move { dest: Vec2f32(100.0, 100.0), queue: false }));
+      }
+      e.tick();
+    }
+    e.tick();
+    let s = units(&e);
+    assert_eq!(handles(&e), children);
+    for (c, x) in s.iter().zip([400.0 - child_rad, 400.0 + child_rad].iter()) {
+      assert_eq!(c.size, 2);
+      assert_eq!(c.body.rad, child_rad);
+      assert_eq!(c.body.pos, Vec2f32(*x, 300.0));
+      assert!(c.selected && c.target.is_none());
+      assert!(matches!(c.state, State::Idle(_)));
+    }
+    // Now they do as they're told
+    e.issue(Command::new(children, Order::Move { dest: Vec2f32(100.0, 100.0), queue: false }));
+    e.tick();
+    assert!(units(&e).iter().all(|s| matches!(s.state, State::Moving(_))));
+  }
+}
@@ -0,0 +1,483 @@
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap};
+use engine::Vec2f32;
+use engine::common::geometry::Rect;
+use entity::Obstacle;
+
+/// Width and height of a navigation grid cell in world coords.
+pub const NAV_CELL_SIZE: f32 = 16.0;
+
+/// Most waypoints a path stores. Longer paths are cut short, and found again
+/// from wherever the splitter man is once he reaches the end of what was
+/// stored.
+pub const MAX_PATH_POINTS: usize = 16;
+
+/// Most flow fields kept cached at once.
+const MAX_FLOW_FIELDS: usize = 16;
+
+/// Cost of moving to an orthogonal neighbour. Diagonal moves cost
+/// DIAGONAL_COST, so costs are ints and paths come out the same on every
+/// machine.
+const STRAIGHT_COST: u32 = 10;
+const DIAGONAL_COST: u32 = 14;
+
+/// Grid over the level bounds, marking which cells a splitter man of a given
+/// radius can stand in without overlapping the bounds or an obstacle which
+/// blocks him.
+pub struct NavGrid {
+  origin: Vec2f32,
+  w: usize,
+  h: usize,
+  walkable: Vec<bool>,
+}
+
+impl NavGrid {
+  pub fn new(bounds: &Rect, obstacles: &[Obstacle], rad: f32) -> NavGrid {
+    let w = (bounds.size.0 / NAV_CELL_SIZE).ceil().max(1.0) as usize;
+    let h = (bounds.size.1 / NAV_CELL_SIZE).ceil().max(1.0) as usize;
+    let mut grid = NavGrid { origin: bounds.pos, w, h, walkable: vec![false; w*h] };
+    for ix in 0..w*h {
+      let c = grid.cell_centre(ix);
+      grid.walkable[ix] = bounds.contains_circle(c, rad)
+        && obstacles.iter().all(|o| !o.blocks(rad) || o.shape.push_out(c, rad).is_none());
+    }
+    return grid;
+  }
+
+  /// Get the index of the cell a point is in, or None if it's off the grid.
+  fn cell_at(&self, p: Vec2f32) -> Option<usize> {
+    let x = ((p.0 - self.origin.0) / NAV_CELL_SIZE).floor();
+    let y = ((p.1 - self.origin.1) / NAV_CELL_SIZE).floor();
+    if x < 0.0 || y < 0.0 || x >= self.w as f32 || y >= self.h as f32 { return None; }
+    return Some(y as usize * self.w + x as usize);
+  }
+
+  fn cell_centre(&self, ix: usize) -> Vec2f32 {
+    Vec2f32(self.origin.0 + ((ix % self.w) as f32 + 0.5) * NAV_CELL_SIZE,
+            self.origin.1 + ((ix / self.w) as f32 + 0.5) * NAV_CELL_SIZE)
+  }
+
+  /// Estimated cost between two cells, the cost of the shortest path if
+  /// there were no obstacles.
+  fn heuristic(&self, a: usize, b: usize) -> u32 {
+    let dx = ((a % self.w) as i64 - (b % self.w) as i64).unsigned_abs() as u32;
+    let dy = ((a / self.w) as i64 - (b / self.w) as i64).unsigned_abs() as u32;
+    return STRAIGHT_COST * dx.max(dy) + (DIAGONAL_COST - STRAIGHT_COST) * dx.min(dy);
+  }
+
+  /// Call `f` with the index and step cost of every walkable neighbour of a
+  /// cell. Diagonal neighbours are skipped if moving to them would cut the
+  /// corner of a cell which isn't walkable.
+  fn for_each_neighbour<F: FnMut(usize, u32)>(&self, ix: usize, mut f: F) {
+    let (x, y) = ((ix % self.w) as i64, (ix / self.w) as i64);
+    for dy in -1..2i64 {
+      for dx in -1..2i64 {
+        if dx == 0 && dy == 0 { continue; }
+        let (nx, ny) = (x + dx, y + dy);
+        if nx < 0 || ny < 0 || nx >= self.w as i64 || ny >= self.h as i64 { continue; }
+        let n = ny as usize * self.w + nx as usize;
+        if !self.walkable[n] { continue; }
+        if dx != 0 && dy != 0 {
+          if !self.walkable[y as usize * self.w + nx as usize]
+            || !self.walkable[ny as usize * self.w + x as usize] {
+              continue;
+            }
+          f(n, DIAGONAL_COST);
+        }
+        else { f(n, STRAIGHT_COST); }
+      }
+    }
+  }
+
+  /// Check every point along a line is in a walkable cell, other than points
+  /// in the `ignore` cell.
+  fn clear_line(&self, a: Vec2f32, b: Vec2f32, ignore: usize) -> bool {
+    let steps = ((b - a).len() / (NAV_CELL_SIZE * 0.25)).ceil().max(1.0) as u32;
+    for ii in 0..steps + 1 {
+      match self.cell_at(a.lerp(&b, ii as f32 / steps as f32)) {
+        Some(ix) if ix == ignore || self.walkable[ix] => continue,
+        _ => return false,
+      }
+    }
+    return true;
+  }
+
+  /// Find a path between two points with A*. The start doesn't need to be
+  /// walkable, since splitter men can be shoved a little into blocked cells
+  /// when sliding along walls.
+  /// # Returns
+  /// The waypoints to visit in order, not including `from`. The last
+  /// waypoint is `to`. None if `from` is off the grid or `to` can't be
+  /// reached.
+  pub fn find_path(&self, from: Vec2f32, to: Vec2f32) -> Option<Vec<Vec2f32>> {
+    return match self.search(from, to) {
+      Some((path, true)) => Some(path),
+      _ => None,
+    };
+  }
+
+  /// Same as find_path, but if the destination can't be reached, the path
+  /// goes to the reachable cell closest to it instead.
+  /// # Returns
+  /// The waypoints to visit in order, not including `from`. The last
+  /// waypoint is where the path ends. None if `from` is off the grid.
+  pub fn find_path_towards(&self, from: Vec2f32, to: Vec2f32) -> Option<Vec<Vec2f32>> {
+    return self.search(from, to).map(|(path, _)| path);
+  }
+
+  /// A* search from `from` towards `to`.
+  /// # Returns
+  /// The path to `to`, or to the closest cell to it if it can't be reached,
+  /// and whether it reaches `to`. None if `from` is off the grid.
+  fn search(&self, from: Vec2f32, to: Vec2f32) -> Option<(Vec<Vec2f32>, bool)> {
+    let start = self.cell_at(from)?;
+    let goal = self.cell_at(to);
+    if let Some(g) = goal {
+      if self.walkable[g] && self.clear_line(from, to, start) { return Some((vec![to], true)); }
+    }
+    // Aim for the goal cell, or if the goal is off the grid, the closest cell
+    // to it
+    let goal = goal.unwrap_or_else(|| {
+      let x = ((to.0 - self.origin.0) / NAV_CELL_SIZE).floor().max(0.0).min(self.w as f32 - 1.0);
+      let y = ((to.1 - self.origin.1) / NAV_CELL_SIZE).floor().max(0.0).min(self.h as f32 - 1.0);
+      y as usize * self.w + x as usize
+    });
+
+    let mut cost = vec![u32::MAX; self.walkable.len()];
+    let mut came_from = vec![usize::MAX; self.walkable.len()];
+    // Ordered by estimated total cost, then the estimate to the goal, then
+    // the cell index, so ties are always broken the same way
+    let mut open = BinaryHeap::new();
+    cost[start] = 0;
+    open.push(Reverse((self.heuristic(start, goal), self.heuristic(start, goal), start)));
+    let mut closest = (self.heuristic(start, goal), start);
+    while let Some(Reverse((f, h, ix))) = open.pop() {
+      // Already found a cheaper way here
+      if cost[ix] + h < f { continue; }
+      if (h, ix) < closest { closest = (h, ix); }
+      if ix == goal { break; }
+      self.for_each_neighbour(ix, |n, step| {
+        let new_cost = cost[ix] + step;
+        if new_cost < cost[n] {
+          cost[n] = new_cost;
+          came_from[n] = ix;
+          let h = self.heuristic(n, goal);
+          open.push(Reverse((new_cost + h, h, n)));
+        }
+      });
+    }
+
+    // Walk back from the end
+    let end = closest.1;
+    let mut cells = vec![end];
+    while *cells.last().unwrap() != start {
+      let prev = came_from[*cells.last().unwrap()];
+      cells.push(prev);
+    }
+    cells.reverse();
+    let reached = end == goal && self.clear_line(self.cell_centre(end), to, start);
+    return Some((self.smooth(&cells, from, to, reached), reached));
+  }
+
+  /// Turn a list of cells into waypoints, cutting out every waypoint we can
+  /// see past.
+  /// # Params
+  /// * `cells`   - The cells of the path, starting with the cell `from` is in.
+  /// * `reached` - True if `to` can be seen from the last cell, so the path
+  ///   can end exactly at `to`.
+  fn smooth(&self, cells: &[usize], from: Vec2f32, to: Vec2f32, reached: bool) -> Vec<Vec2f32> {
+    let start = cells[0];
+    // Only the cells where the path turns can be waypoints, the path runs
+    // straight between them
+    let dir = |a: usize, b: usize| b as i64 - a as i64;
+    let mut points = vec![from];
+    for ii in 1..cells.len() - 1 {
+      if dir(cells[ii - 1], cells[ii]) != dir(cells[ii], cells[ii + 1]) {
+        points.push(self.cell_centre(cells[ii]));
+      }
+    }
+    if cells.len() > 1 { points.push(self.cell_centre(cells[cells.len() - 1])); }
+    if reached {
+      if self.cell_at(to) == Some(cells[cells.len() - 1]) { *points.last_mut().unwrap() = to; }
+      else { points.push(to); }
+    }
+    let last = points.len() - 1;
+    let mut path = Vec::new();
+    let mut anchor = 0;
+    for ii in 1..last {
+      if !self.clear_line(points[anchor], points[ii + 1], start) {
+        path.push(points[ii]);
+        anchor = ii;
+      }
+    }
+    path.push(points[last]);
+    return path;
+  }
+
+  /// Find the cost to reach a destination from every cell, so paths to it
+  /// can be found from anywhere without searching.
+  /// # Returns
+  /// None if the destination is off the grid or isn't walkable.
+  pub fn flow_field(&self, to: Vec2f32) -> Option<FlowField> {
+    let goal = self.cell_at(to)?;
+    if !self.walkable[goal] { return None; }
+    let mut cost = vec![u32::MAX; self.walkable.len()];
+    let mut open = BinaryHeap::new();
+    cost[goal] = 0;
+    open.push(Reverse((0, goal)));
+    while let Some(Reverse((c, ix))) = open.pop() {
+      if c > cost[ix] { continue; }
+      self.for_each_neighbour(ix, |n, step| {
+        if c + step < cost[n] {
+          cost[n] = c + step;
+          open.push(Reverse((c + step, n)));
+        }
+      });
+    }
+    return Some(FlowField { goal, cost });
+  }
+
+  /// Find a path by following a flow field downhill from `from` towards the
+  /// field's destination, until `to` is in sight. `to` should be near the
+  /// field's destination, i.e. a spot in a formation around it.
+  /// # Returns
+  /// The waypoints to visit in order, not including `from`. None if `from`
+  /// is off the grid, or `to` can't be seen from anywhere on the way to the
+  /// field's destination.
+  pub fn path_from_field(&self, field: &FlowField, from: Vec2f32, to: Vec2f32) -> Option<Vec<Vec2f32>> {
+    let start = self.cell_at(from)?;
+    if self.clear_line(from, to, start) { return Some(vec![to]); }
+    // Only start looking for `to` once we're about as close to it as the
+    // field's destination is
+    let near = (to - self.cell_centre(field.goal)).len() + NAV_CELL_SIZE*2.0;
+    let mut cells = vec![start];
+    let mut ix = start;
+    loop {
+      let c = self.cell_centre(ix);
+      if (c - to).len() <= near && self.clear_line(c, to, start) { break; }
+      if ix == field.goal { return None; }
+      // Step to the cheapest neighbour. The start might not be walkable, so
+      // has no cost of its own.
+      let mut best = (field.cost[ix], ix);
+      self.for_each_neighbour(ix, |n, _| {
+        if field.cost[n] < best.0 { best = (field.cost[n], n); }
+      });
+      if best.1 == ix { return None; }
+      ix = best.1;
+      cells.push(ix);
+    }
+    return Some(self.smooth(&cells, from, to, true));
+  }
+}
+
+/// Cost to reach a destination cell from every cell of a nav grid. Found once
+/// per destination and shared by every splitter man heading there.
+pub struct FlowField {
+  goal: usize,
+  cost: Vec<u32>,
+}
+
+/// Navigation grids for every radius of splitter man which has asked for a
+/// path, along with the obstacles they were built from, and flow fields to
+/// recent group move destinations.
+pub struct NavCache {
+  obstacles: Vec<Obstacle>,
+  /// Keyed by the bits of the radius
+  grids: HashMap<u32, NavGrid>,
+  /// Keyed by the bits of the radius and the index of the destination cell.
+  /// None if the destination can't be walked to.
+  fields: HashMap<(u32, usize), Option<FlowField>>,
+}
+
+impl NavCache {
+  pub fn new(obstacles: Vec<Obstacle>) -> NavCache {
+    NavCache { obstacles, grids: HashMap::new(), fields: HashMap::new() }
+  }
+
+  /// Throw away every grid if the obstacles have changed, i.e. a door has
+  /// opened.
+  /// # Returns
+  /// True if the obstacles changed, so any paths found with the old grids
+  /// are out of date.
+  pub fn set_obstacles(&mut self, obstacles: Vec<Obstacle>) -> bool {
+    if obstacles == self.obstacles { return false; }
+    self.obstacles = obstacles;
+    self.grids.clear();
+    self.fields.clear();
+    return true;
+  }
+
+  /// Get the grid for a radius, building it if we haven't already.
+  pub fn grid(&mut self, bounds: &Rect, rad: f32) -> &NavGrid {
+    let obstacles = &self.obstacles;
+    self.grids.entry(rad.to_bits()).or_insert_with(|| NavGrid::new(bounds, obstacles, rad))
+  }
+
+  /// Find a path to `to` using the flow field to `group_dest`'s cell,
+  /// finding the flow field first if it isn't cached. Falls back to A* if
+  /// the flow field can't get there.
+  pub fn find_group_path(&mut self, bounds: &Rect, rad: f32, from: Vec2f32, to: Vec2f32, 
+                         group_dest: Vec2f32) -> Option<Vec<Vec2f32>> {
+    self.grid(bounds, rad);
+    let grid = &self.grids[&rad.to_bits()];
+    let goal = match grid.cell_at(group_dest) { Some(g) => g, None => return grid.find_path_towards(from, to) };
+    if !self.fields.contains_key(&(rad.to_bits(), goal)) {
+      // Just forget every field once there are too many, destinations are
+      // rarely reused for long anyway
+      if self.fields.len() >= MAX_FLOW_FIELDS { self.fields.clear(); }
+      self.fields.insert((rad.to_bits(), goal), grid.flow_field(group_dest));
+    }
+    return match self.fields[&(rad.to_bits(), goal)] {
+      Some(ref field) => grid.path_from_field(field, from, to).or_else(|| grid.find_path_towards(from, to)),
+      None => grid.find_path_towards(from, to),
+    };
+  }
+}
+
+/// Waypoints a splitter man is following, stored inline so splitter men stay
+/// Copy.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Path {
+  points: [Vec2f32; MAX_PATH_POINTS],
+  len: usize,
+  /// Index of the next waypoint to head to
+  next: usize,
+  /// False if the path was too long to store, so the last waypoint isn't the
+  /// end of the path
+  pub complete: bool,
+}
+
+impl Path {
+  /// Create a path, keeping only the first MAX_PATH_POINTS waypoints.
+  pub fn new(points: &[Vec2f32]) -> Path {
+    let mut path = Path {
+      points: [Vec2f32(0.0, 0.0); MAX_PATH_POINTS],
+      len: points.len().min(MAX_PATH_POINTS),
+      next: 0,
+      complete: points.len() <= MAX_PATH_POINTS,
+    };
+    path.points[..path.len].copy_from_slice(&points[..path.len]);
+    return path;
+  }
+
+  /// Every waypoint stored, including ones already visited.
+  pub fn points(&self) -> &[Vec2f32] { &self.points[..self.len] }
+
+  /// Index of the next waypoint to head to. Equal to the number of points
+  /// once the path has been followed to the end.
+  pub fn next_index(&self) -> usize { self.next }
+
+  /// Set the index of the next waypoint, clamped to the number of points.
+  pub fn set_next_index(&mut self, next: usize) { self.next = next.min(self.len); }
+
+  /// The next waypoint to head to, or None if there are none left.
+  pub fn next_point(&self) -> Option<Vec2f32> {
+    if self.next < self.len { Some(self.points[self.next]) } else { None }
+  }
+
+  /// Move on to the next waypoint.
+  pub fn advance(&mut self) {
+    if self.next < self.len { self.next += 1; }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use engine::common::geometry::Shape;
+  use entity::ObstacleKind;
+
+  const RAD: f32 = 8.0;
+
+  fn wall(x: f32, y: f32, w: f32, h: f32) -> Obstacle {
+    Obstacle::new(Shape::Rect(Rect::new(x, y, w, h)), ObstacleKind::Wall)
+  }
+
+  /// A 400 by 400 level split by a wall down the middle, with a way round
+  /// at the bottom.
+  fn grid() -> NavGrid {
+    NavGrid::new(&Rect::new(0.0, 0.0, 400.0, 400.0), &[wall(180.0, 0.0, 40.0, 320.0)], RAD)
+  }
+
+  fn length(from: Vec2f32, path: &[Vec2f32]) -> f32 {
+    let mut len = 0.0;
+    let mut prev = from;
+    for p in path { len += (*p - prev).len(); prev = *p; }
+    return len;
+  }
+
+  /// True if no point along the path comes within RAD of the wall.
+  fn misses_wall(from: Vec2f32, path: &[Vec2f32]) -> bool {
+    let mut prev = from;
+    for p in path {
+      for ii in 0..=32 {
+        let q = prev.lerp(p, ii as f32 / 32.0);
+        if q.0 > 180.0 - RAD && q.0 < 220.0 + RAD && q.1 < 320.0 + RAD { return false; }
+      }
+      prev = *p;
+    }
+    return true;
+  }
+
+  #[test]
+  fn straight_when_clear() {
+    let path = grid().find_path(Vec2f32(40.0, 40.0), Vec2f32(100.0, 300.0)).unwrap();
+    assert_eq!(path, vec![Vec2f32(100.0, 300.0)]);
+  }
+
+  #[test]
+  fn routes_around_wall() {
+    let (from, to) = (Vec2f32(100.0, 100.0), Vec2f32(300.0, 100.0));
+    let path = grid().find_path(from, to).unwrap();
+    assert_eq!(*path.last().unwrap(), to);
+    assert!(path.len() > 1);
+    assert!(misses_wall(from, &path), "{:?}", path);
+  }
+
+  #[test]
+  fn unreachable_gives_none() {
+    // Wall right across the level
+    let g = NavGrid::new(&Rect::new(0.0, 0.0, 400.0, 400.0), &[wall(180.0, 0.0, 40.0, 400.0)], RAD);
+    let (from, to) = (Vec2f32(100.0, 100.0), Vec2f32(300.0, 100.0));
+    assert!(g.find_path(from, to).is_none());
+    // Goes as close as it can instead
+    let towards = g.find_path_towards(from, to).unwrap();
+    assert!(towards.last().unwrap().0 < 180.0);
+    // Off the grid
+    assert!(g.find_path(Vec2f32(-50.0, 100.0), from).is_none());
+  }
+
+  #[test]
+  fn flow_field_paths_match_a_star() {
+    let g = grid();
+    let to = Vec2f32(300.0, 100.0);
+    let field = g.flow_field(to).unwrap();
+    for &from in &[Vec2f32(100.0, 100.0), Vec2f32(20.0, 380.0), Vec2f32(150.0, 20.0), Vec2f32(380.0, 380.0)] {
+      let a_star = g.find_path(from, to).unwrap();
+      let flow = g.path_from_field(&field, from, to).unwrap();
+      assert_eq!(*flow.last().unwrap(), to);
+      assert!(misses_wall(from, &flow), "{:?}", flow);
+      let (a, f) = (length(from, &a_star), length(from, &flow));
+      assert!((a - f).abs() <= NAV_CELL_SIZE, "A* {} flow field {} from {:?}", a, f, from);
+    }
+  }
+
+  #[test]
+  fn flow_field_to_blocked_cell() {
+    assert!(grid().flow_field(Vec2f32(200.0, 100.0)).is_none());
+    assert!(grid().flow_field(Vec2f32(500.0, 100.0)).is_none());
+  }
+
+  #[test]
+  fn group_paths_share_a_field() {
+    let mut cache = NavCache::new(vec![wall(180.0, 0.0, 40.0, 320.0)]);
+    let bounds = Rect::new(0.0, 0.0, 400.0, 400.0);
+    let dest = Vec2f32(300.0, 100.0);
+    for ii in 0..4 {
+      let to = dest + Vec2f32(ii as f32 * 20.0, 0.0);
+      let path = cache.find_group_path(&bounds, RAD, Vec2f32(100.0, 100.0 + ii as f32 * 20.0), to, dest).unwrap();
+      assert_eq!(*path.last().unwrap(), to);
+    }
+    assert_eq!(cache.fields.len(), 1);
+  }
+}
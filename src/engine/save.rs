@@ -0,0 +1,464 @@
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::path::Path;
+use std::str::SplitWhitespace;
+use engine::{Engine, EntityList, Vec2f32};
+use engine::nav;
+use level::Level;
+use entity::{Entity, EHandle, SplitterMan, Obstacle, ObstacleKind, PressurePlate};
+use engine::common::geometry::{Rect, ConvexPoly, Shape};
+use entity::splitter_man::{State, IdleState, MovingState, SplittingState, JoiningState,
+                             HoldingState, PatrollingState, QueuedMove};
+
+/// Version written to the header of save files. Bump this if the format
+/// changes.
+pub const SAVE_VERSION: u32 = 10;
+
+/// First word of every save file.
+const SAVE_MAGIC: &str = "splitterman-save";
+
+// # Save file format
+// Save files are text, one record per line, with fields separated by spaces.
+// Floats are written with enough digits to be read back exactly.
+//
+// splitterman-save <version>
+// level <line count>
+// <the level file, line count lines of it>
+// tick <frames run so far>
+// free <slot index>...
+// slot <generation> empty
+// slot <generation> splitter_man <pos x> <pos y> <vel x> <vel y> <rad>
+//      <size> <selected 0|1> <groups> <speed> <target x> <target y> | none
+//      <group target x> <group target y> | none <path> <queue> <state>
+// slot <generation> obstacle <kind> rect <x> <y> <w> <h>
+// slot <generation> obstacle <kind> poly <vertex count> <x> <y>...
+// slot <generation> plate <x> <y> <w> <h> <threshold> <channel> <pressed 0|1>
+//
+// The level is the level file the save was made on, as it was loaded, so the
+// obstacles and goals match the entities. Its line count is 0 if no level was
+// loaded. There is one `slot` line per slot in the entity list, in slot order, so the
+// n-th slot line is slot n and the entity in it has the handle
+// (n, generation). `free` lists the empty slots in the order they'll be
+// reused. <path> is `none`, or
+// `<complete 0|1> <next waypoint> <waypoint count> <x> <y>...`. <queue> is
+// `<queued move count>` followed by
+// `<target x> <target y> <group target x> <group target y> | none` for each
+// queued move. <state> is one of:
+//
+// idle
+// moving
+// joining <leader slot> <leader generation> <ticks spent joining>
+// holding
+// patrolling
+// splitting <tick> <duration> <from x> <from y> <to x> <to y> <from rad> <to rad>
+//
+// and <kind> is one of:
+//
+// wall
+// gap <max rad>
+// door <channel> <open 0|1>
+
+impl Engine {
+  /// Save the level, every entity, and everything needed to carry on handing
+  /// out handles exactly where we left off, to a file.
+  pub fn save<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+    let mut out = BufWriter::new(File::create(path)?);
+    self.write_save(&mut out)?;
+    out.flush()
+  }
+
+  /// Replace the world, and the level, with one saved to a file. If the file
+  /// can't be loaded, the world is left as it was.
+  pub fn load<P: AsRef<Path>>(&mut self, path: P) -> io::Result<()> {
+    self.read_save(BufReader::new(File::open(path)?))
+  }
+
+  pub fn write_save(&self, out: &mut dyn Write) -> io::Result<()> {
+    writeln!(out, "{} {}", SAVE_MAGIC, SAVE_VERSION)?;
+    let level = self.level.as_ref().map_or("", |l| l.source.as_str());
+    writeln!(out, "level {}", level.lines().count())?;
+    for line in level.lines() { writeln!(out, "{}", line)?; }
+    writeln!(out, "tick {}", self.tick_count)?;
+    let (slots, free) = self.entity_list.to_raw();
+    write!(out, "free")?;
+    for ix in free { write!(out, " {}", ix)?; }
+    writeln!(out)?;
+    for (generation, e) in slots {
+      write!(out, "slot {}", generation)?;
+      match e {
+        None => write!(out, " empty")?,
+        Some(Entity::SplitterMan(s)) => write_splitter_man(out, &s)?,
+        Some(Entity::Obstacle(o)) => write_obstacle(out, &o)?,
+        Some(Entity::PressurePlate(p)) => write_plate(out, &p)?,
+      }
+      writeln!(out)?;
+    }
+    Ok(())
+  }
+
+  pub fn read_save<R: BufRead>(&mut self, reader: R) -> io::Result<()> {
+    let mut level = None;
+    let mut tick_count = None;
+    let mut free = None;
+    let mut slots = Vec::new();
+    let mut lines = reader.lines().enumerate();
+    while let Some((ii, line)) = lines.next() {
+      let line = line?;
+      let line_num = ii + 1;
+      let bad = || io::Error::new(io::ErrorKind::InvalidData,
+                                  format!("malformed save file on line {}", line_num));
+      let mut words = line.split_whitespace();
+      if ii == 0 {
+        if words.next() != Some(SAVE_MAGIC) || parse::<u32>(&mut words) != Some(SAVE_VERSION) {
+          return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                    format!("not a version {} save file", SAVE_VERSION)));
+        }
+        continue;
+      }
+      match words.next() {
+        Some("level") => {
+          let n: usize = parse(&mut words).ok_or_else(&bad)?;
+          let mut src = String::new();
+          for _ in 0..n {
+            match lines.next() {
+              Some((_, l)) => { src.push_str(&l?); src.push('\n'); },
+              None => return Err(bad()),
+            }
+          }
+          level = Some(src);
+        },
+        Some("tick") => tick_count = Some(parse(&mut words).ok_or_else(&bad)?),
+        Some("free") => {
+          let mut ixs = Vec::new();
+          for w in words.by_ref() { ixs.push(w.parse().map_err(|_| bad())?); }
+          free = Some(ixs);
+        },
+        Some("slot") => {
+          let generation = parse(&mut words).ok_or_else(&bad)?;
+          let h = EHandle(slots.len() as u32, generation);
+          let e = match words.next() {
+            Some("empty") => None,
+            Some("splitter_man") => {
+              let mut s = read_splitter_man(&mut words).ok_or_else(&bad)?;
+              s.id = Some(h);
+              Some(Entity::SplitterMan(s))
+            },
+            Some("obstacle") => {
+              let mut o = read_obstacle(&mut words).ok_or_else(&bad)?;
+              o.id = Some(h);
+              Some(Entity::Obstacle(o))
+            },
+            Some("plate") => {
+              let mut p = read_plate(&mut words).ok_or_else(&bad)?;
+              p.id = Some(h);
+              Some(Entity::PressurePlate(p))
+            },
+            _ => return Err(bad()),
+          };
+          slots.push((generation, e));
+        },
+        None => continue,
+        _ => return Err(bad()),
+      }
+      if words.next().is_some() { return Err(bad()); }
+    }
+    let bad = |msg: &str| io::Error::new(io::ErrorKind::InvalidData, msg.to_string());
+    let level = match level.ok_or_else(|| bad("save file has no level line"))? {
+      ref src if src.is_empty() => None,
+      src => match Level::parse(&src) {
+        Ok(l) => Some(l),
+        Err(errors) => return Err(bad(&format!("save file's level is invalid: {}", errors[0]))),
+      },
+    };
+    let tick_count = tick_count.ok_or_else(|| bad("save file has no tick line"))?;
+    let free = free.ok_or_else(|| bad("save file has no free line"))?;
+    let list = EntityList::from_raw(slots, free)
+      .ok_or_else(|| bad("save file's free list doesn't match its empty slots"))?;
+    self.entity_list = list;
+    self.level = level;
+    self.tick_count = tick_count;
+    // Clicks and group taps from before the load don't carry over
+    self.last_click = None;
+    self.last_group_recall = None;
+    self.reset_nav();
+    Ok(())
+  }
+}
+
+fn parse<T: ::std::str::FromStr>(words: &mut SplitWhitespace) -> Option<T> {
+  words.next()?.parse().ok()
+}
+
+fn parse_vec(words: &mut SplitWhitespace) -> Option<Vec2f32> {
+  Some(Vec2f32(parse(words)?, parse(words)?))
+}
+
+fn write_vec(out: &mut dyn Write, v: Vec2f32) -> io::Result<()> {
+  write!(out, " {:?} {:?}", v.0, v.1)
+}
+
+fn write_splitter_man(out: &mut dyn Write, s: &SplitterMan) -> io::Result<()> {
+  write!(out, " splitter_man")?;
+  write_vec(out, s.body.pos)?;
+  write_vec(out, s.body.vel)?;
+  write!(out, " {:?} {} {} {} {:?}", s.body.rad, s.size, s.selected as u8, s.groups, s.speed)?;
+  match s.target {
+    Some(t) => write_vec(out, t)?,
+    None => write!(out, " none")?,
+  }
+  match s.group_target {
+    Some(t) => write_vec(out, t)?,
+    None => write!(out, " none")?,
+  }
+  match s.path {
+    Some(ref p) => {
+      write!(out, " {} {} {}", p.complete as u8, p.next_index(), p.points().len())?;
+      for v in p.points() { write_vec(out, *v)?; }
+    },
+    None => write!(out, " none")?,
+  }
+  write!(out, " {}", s.queue.len())?;
+  for m in s.queue.moves() {
+    write_vec(out, m.target)?;
+    match m.group_target {
+      Some(t) => write_vec(out, t)?,
+      None => write!(out, " none")?,
+    }
+  }
+  match s.state {
+    State::Idle(_) => write!(out, " idle"),
+    State::Moving(_) => write!(out, " moving"),
+    State::Joining(ref st) => write!(out, " joining {} {} {}", st.leader.0, st.leader.1, st.tick),
+    State::Holding(_) => write!(out, " holding"),
+    State::Patrolling(_) => write!(out, " patrolling"),
+    State::Splitting(ref st) => {
+      write!(out, " splitting {} {}", st.tick, st.duration)?;
+      write_vec(out, st.from)?;
+      write_vec(out, st.to)?;
+      write!(out, " {:?} {:?}", st.from_rad, st.to_rad)
+    },
+  }
+}
+
+fn read_splitter_man(words: &mut SplitWhitespace) -> Option<SplitterMan> {
+  let pos = parse_vec(words)?;
+  let vel = parse_vec(words)?;
+  let rad = parse(words)?;
+  let size = parse(words)?;
+  let selected = parse_bool(words)?;
+  let groups = parse(words)?;
+  let speed = parse(words)?;
+  let target = match words.clone().next()? {
+    "none" => { words.next(); None },
+    _ => Some(parse_vec(words)?),
+  };
+  let group_target = match words.clone().next()? {
+    "none" => { words.next(); None },
+    _ => Some(parse_vec(words)?),
+  };
+  let path = match words.clone().next()? {
+    "none" => { words.next(); None },
+    _ => {
+      let complete = parse_bool(words)?;
+      let next = parse(words)?;
+      let n: usize = parse(words)?;
+      let mut points = Vec::new();
+      for _ in 0..n { points.push(parse_vec(words)?); }
+      let mut p = nav::Path::new(&points);
+      p.complete = complete;
+      p.set_next_index(next);
+      Some(p)
+    },
+  };
+  let n: usize = parse(words)?;
+  let mut queue = Vec::new();
+  for _ in 0..n {
+    let target = parse_vec(words)?;
+    let group_target = match words.clone().next()? {
+      "none" => { words.next(); None },
+      _ => Some(parse_vec(words)?),
+    };
+    queue.push(QueuedMove { target, group_target });
+  }
+  let state = match words.next()? {
+    "idle" => State::Idle(IdleState::new()),
+    "moving" => State::Moving(MovingState::new()),
+    "joining" => {
+      let mut st = JoiningState::new(EHandle(parse(words)?, parse(words)?));
+      st.tick = parse(words)?;
+      State::Joining(st)
+    },
+    "holding" => State::Holding(HoldingState::new()),
+    "patrolling" => State::Patrolling(PatrollingState::new()),
+    "splitting" => {
+      let mut st = SplittingState::new(0, Vec2f32(0.0, 0.0), Vec2f32(0.0, 0.0), 0.0, 0.0);
+      st.tick = parse(words)?;
+      st.duration = parse(words)?;
+      st.from = parse_vec(words)?;
+      st.to = parse_vec(words)?;
+      st.from_rad = parse(words)?;
+      st.to_rad = parse(words)?;
+      State::Splitting(st)
+    },
+    _ => return None,
+  };
+  let mut s = SplitterMan::new(pos.0, pos.1, size);
+  s.body.vel = vel;
+  s.body.rad = rad;
+  s.selected = selected;
+  s.groups = groups;
+  s.speed = speed;
+  s.target = target;
+  s.group_target = group_target;
+  s.path = path;
+  for m in queue {
+    if !s.queue.push(m) { return None; }
+  }
+  s.state = state;
+  return Some(s);
+}
+
+fn parse_bool(words: &mut SplitWhitespace) -> Option<bool> {
+  match words.next()? { "0" => Some(false), "1" => Some(true), _ => None }
+}
+
+fn write_obstacle(out: &mut dyn Write, o: &Obstacle) -> io::Result<()> {
+  write!(out, " obstacle")?;
+  match o.kind {
+    ObstacleKind::Wall => write!(out, " wall")?,
+    ObstacleKind::Gap(max_rad) => write!(out, " gap {:?}", max_rad)?,
+    ObstacleKind::Door { channel, open } => write!(out, " door {} {}", channel, open as u8)?,
+  }
+  match o.shape {
+    Shape::Rect(ref r) => {
+      write!(out, " rect")?;
+      write_vec(out, r.pos)?;
+      write_vec(out, r.size)
+    },
+    Shape::Poly(ref p) => {
+      write!(out, " poly {}", p.verts().len())?;
+      for v in p.verts() { write_vec(out, *v)?; }
+      Ok(())
+    },
+  }
+}
+
+fn read_obstacle(words: &mut SplitWhitespace) -> Option<Obstacle> {
+  let kind = match words.next()? {
+    "wall" => ObstacleKind::Wall,
+    "gap" => ObstacleKind::Gap(parse(words)?),
+    "door" => ObstacleKind::Door { channel: parse(words)?, open: parse_bool(words)? },
+    _ => return None,
+  };
+  let shape = match words.next()? {
+    "rect" => {
+      let (pos, size) = (parse_vec(words)?, parse_vec(words)?);
+      Shape::Rect(Rect { pos, size })
+    },
+    "poly" => {
+      let n: usize = parse(words)?;
+      let mut verts = Vec::new();
+      for _ in 0..n { verts.push(parse_vec(words)?); }
+      Shape::Poly(ConvexPoly::new(&verts)?)
+    },
+    _ => return None,
+  };
+  return Some(Obstacle::new(shape, kind));
+}
+
+fn write_plate(out: &mut dyn Write, p: &PressurePlate) -> io::Result<()> {
+  write!(out, " plate")?;
+  write_vec(out, p.area.pos)?;
+  write_vec(out, p.area.size)?;
+  write!(out, " {} {} {}", p.threshold, p.channel, p.pressed as u8)
+}
+
+fn read_plate(words: &mut SplitWhitespace) -> Option<PressurePlate> {
+  let (pos, size) = (parse_vec(words)?, parse_vec(words)?);
+  let mut p = PressurePlate::new(Rect { pos, size }, parse(words)?, parse(words)?);
+  p.pressed = parse_bool(words)?;
+  return Some(p);
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use engine::command::{Command, Order};
+  use input::QueuedInput;
+  use level::Level;
+
+  const LEVEL: &str = "bounds 0 0 800 600\nbox 300 100 50 300\npoly 500 100 560 100 530 160\n\
+                       gap 600 400 40 40 9\ndoor 650 0 20 100 1\nplate 40 40 40 40 2 1\n\
+                       spawn 100 300 4\nspawn 140 300 2\nspawn 100 500 1\nspawn 300 500 1\n";
+
+  fn snapshot(e: &Engine) -> String {
+    let mut out = Vec::new();
+    e.write_save(&mut out).unwrap();
+    return String::from_utf8(out).unwrap();
+  }
+
+  #[test]
+  fn save_load_round_trip() {
+    let mut e = Engine::new_headless(800, 600, Box::new(QueuedInput::new()));
+    e.load_level(Level::parse(LEVEL).unwrap());
+    let h = e.units_in_box(Vec2f32(0.0, 0.0), Vec2f32(800.0, 600.0));
+    // Catch units part way through moving, queueing, splitting and joining,
+    // and leave an empty slot behind
+    e.issue(Command::new(vec![h[0]], Order::Move { dest: Vec2f32(700.0, 300.0), queue: false }));
+    e.issue(Command::new(vec![h[0]], Order::Move { dest: Vec2f32(700.0, 500.0), queue: true }));
+    e.issue(Command::new(vec![h[1]], Order::Split));
+    e.issue(Command::new(vec![h[2], h[3]], Order::Join));
+    for _ in 0..5 { e.tick(); }
+    e.entity_list.remove(h[1]);
+    let saved = snapshot(&e);
+    for word in &[" splitting ", " joining ", " empty", " moving", " door ", " plate "] {
+      assert!(saved.contains(word), "no{} in {}", word, saved);
+    }
+
+    let mut loaded = Engine::new_headless(800, 600, Box::new(QueuedInput::new()));
+    loaded.read_save(saved.as_bytes()).unwrap();
+    assert_eq!(snapshot(&loaded), saved);
+    assert_eq!(loaded.checksum(), e.checksum());
+    // Handles carry on from the same place
+    let unit = Entity::SplitterMan(SplitterMan::new(0.0, 0.0, 1));
+    assert_eq!(loaded.add_entity(unit), e.add_entity(unit));
+  }
+
+  #[test]
+  fn loading_brings_its_level() {
+    let mut e = Engine::new_headless(800, 600, Box::new(QueuedInput::new()));
+    e.load_level(Level::parse(LEVEL).unwrap());
+    for _ in 0..5 { e.tick(); }
+    let saved = snapshot(&e);
+
+    let mut other = Engine::new_headless(800, 600, Box::new(QueuedInput::new()));
+    other.load_level(Level::parse("bounds 0 0 200 200\nbox 50 50 20 20\nspawn 10 10 1\n").unwrap());
+    other.read_save(saved.as_bytes()).unwrap();
+    let level = other.level.as_ref().unwrap();
+    assert_eq!(level.source, LEVEL);
+    assert_eq!(level.bounds, e.level.as_ref().unwrap().bounds);
+    assert_eq!(snapshot(&other), saved);
+    // Paths are found around the saved level's obstacles, not the old ones
+    let path = other.find_path(Vec2f32(250.0, 250.0), Vec2f32(400.0, 250.0), 8.0).unwrap();
+    assert!(path.iter().all(|p| p.0 < 300.0 - 8.0 || p.0 > 350.0 + 8.0 || p.1 > 400.0 + 8.0));
+    for _ in 0..5 { other.tick(); e.tick(); }
+    assert_eq!(other.checksum(), e.checksum());
+
+    // No level saves as none
+    let mut bare = Engine::new_headless(800, 600, Box::new(QueuedInput::new()));
+    bare.read_save(snapshot(&Engine::new_headless(800, 600, Box::new(QueuedInput::new()))).as_bytes()).unwrap();
+    assert!(bare.level.is_none());
+  }
+
+  #[test]
+  fn rejects_bad_saves() {
+    let mut e = Engine::new_headless(800, 600, Box::new(QueuedInput::new()));
+    assert!(e.read_save("splitterman-save 1\ntick 0\nfree\n".as_bytes()).is_err());
+    let err = e.read_save(format!("{} {}\ntick 0\nfree\nslot 0 bogus\n", SAVE_MAGIC, SAVE_VERSION).as_bytes());
+    assert!(err.unwrap_err().to_string().contains("line 4"));
+    let no_level = e.read_save(format!("{} {}\ntick 0\nfree\n", SAVE_MAGIC, SAVE_VERSION).as_bytes());
+    assert!(no_level.unwrap_err().to_string().contains("no level"));
+    let short_level = e.read_save(format!("{} {}\nlevel 3\nbounds 0 0 10 10\n", SAVE_MAGIC, SAVE_VERSION).as_bytes());
+    assert!(short_level.is_err());
+  }
+}
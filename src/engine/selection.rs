@@ -0,0 +1,233 @@
+use entity::{Entity, EHandle};
+use engine::{Engine, Vec2f32};
+use engine::common::geometry::point_in_polygon;
+use input::Control;
+
+/// Selection boxes smaller than this many pixels across are clicks.
+const CLICK_SIZE: f32 = 4.0;
+
+/// Clicking the same splitter man twice within this many frames is a double
+/// click.
+const DOUBLE_CLICK_TICKS: u64 = 20;
+
+/// How newly picked splitter men change the selection.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SelectMode {
+  /// Select just the picked splitter men.
+  Replace,
+  /// Add the picked splitter men to the selection.
+  Add,
+  /// Select the picked splitter men which weren't selected, and deselect the
+  /// ones which were.
+  Toggle,
+}
+
+impl Engine {
+  /// Change the selection from this frame's input. A box selects every
+  /// splitter man it touches, a lasso selects every splitter man whose centre
+  /// is inside it, a click selects the one under the cursor, and a double
+  /// click selects every splitter man on screen the same size as the one
+  /// under the cursor. Holding the add or toggle controls changes how
+  /// they're combined with the current selection.
+  pub fn update_selection(&mut self) {
+    if self.input_handler.inputs.get(&Control::SelectAll).unwrap().just_down {
+      self.select_all();
+    }
+    let inputs = &self.input_handler.inputs;
+    let mut mode = SelectMode::Replace;
+    if inputs.get(&Control::ToggleSelect).unwrap().down { mode = SelectMode::Toggle; }
+    else if inputs.get(&Control::AddSelect).unwrap().down { mode = SelectMode::Add; }
+
+    let hits;
+    if let Some(sel) = self.input_handler.selection {
+      if (sel[1].0 - sel[0].0).abs() < CLICK_SIZE && (sel[1].1 - sel[0].1).abs() < CLICK_SIZE {
+        hits = self.click(sel[1], &mut mode);
+      }
+      else {
+        let a = self.camera.screen_to_world(sel[0].0 as i32, sel[0].1 as i32);
+        let b = self.camera.screen_to_world(sel[1].0 as i32, sel[1].1 as i32);
+        hits = self.units_in_box(a, b);
+      }
+    }
+    else if let Some(lasso) = self.input_handler.lasso.clone() {
+      // Too small to be a lasso
+      if lasso.len() < 3 { hits = self.click(lasso[lasso.len() - 1], &mut mode); }
+      else {
+        let poly: Vec<Vec2f32> = lasso.iter().map(|p| self.camera.screen_to_world(p.0 as i32, p.1 as i32)).collect();
+        hits = self.units_in_polygon(&poly);
+      }
+    }
+    else { return; }
+    self.select(&hits, mode);
+  }
+
+  /// Pick the splitter man under a click at `p` in screen coords, or if it's
+  /// the second click on him, every one like him on screen.
+  fn click(&mut self, p: Vec2f32, mode: &mut SelectMode) -> Vec<EHandle> {
+    let picked = self.unit_at(self.camera.screen_to_world(p.0 as i32, p.1 as i32));
+    let double = match (picked, self.last_click) {
+      (Some(h), Some((last, tick))) => h == last && tick <= self.tick_count && self.tick_count - tick <= DOUBLE_CLICK_TICKS,
+      _ => false,
+    };
+    if double {
+      self.last_click = None;
+      // Toggling would deselect the one we just clicked
+      if *mode == SelectMode::Toggle { *mode = SelectMode::Add; }
+      return self.on_screen_of_size(picked.unwrap());
+    }
+    self.last_click = picked.map(|h| (h, self.tick_count));
+    return picked.into_iter().collect();
+  }
+
+  /// Change which splitter men are selected.
+  pub fn select(&mut self, hits: &[EHandle], mode: SelectMode) {
+    let mut hits = hits.to_vec();
+    hits.sort();
+    for e in &self.entity_list {
+      if let Entity::SplitterMan(mut s) = e.get() {
+        let hit = hits.binary_search(&s.id.unwrap()).is_ok();
+        match mode {
+          SelectMode::Replace => s.selected = hit,
+          SelectMode::Add => s.selected |= hit,
+          SelectMode::Toggle => if hit { s.selected = !s.selected },
+        }
+        e.set(Entity::SplitterMan(s));
+      }
+    }
+  }
+
+  /// Select every splitter man.
+  pub fn select_all(&mut self) {
+    let all = self.units_in_box(Vec2f32(f32::MIN, f32::MIN), Vec2f32(f32::MAX, f32::MAX));
+    self.select(&all, SelectMode::Replace);
+  }
+
+  /// The splitter man under a point in world coords. If several overlap it,
+  /// the one whose centre is closest.
+  pub fn unit_at(&self, p: Vec2f32) -> Option<EHandle> {
+    let mut best: Option<(f32, EHandle)> = None;
+    for e in &self.entity_list {
+      if let Entity::SplitterMan(s) = e.get() {
+        let d = p - s.body.pos;
+        let rad = s.get_size();
+        if d.0.abs() > rad || d.1.abs() > rad { continue; }
+        if best.is_none() || d.len2() < best.unwrap().0 { best = Some((d.len2(), s.id.unwrap())); }
+      }
+    }
+    return best.map(|b| b.1);
+  }
+
+  /// Every splitter man touching the box with corners `a` and `b` in world
+  /// coords, in handle order.
+  pub fn units_in_box(&self, a: Vec2f32, b: Vec2f32) -> Vec<EHandle> {
+    let (min, max) = (Vec2f32(a.0.min(b.0), a.1.min(b.1)), Vec2f32(a.0.max(b.0), a.1.max(b.1)));
+    let mut handles = Vec::new();
+    for e in &self.entity_list {
+      if let Entity::SplitterMan(s) = e.get() {
+        let (pos, rad) = (s.body.pos, s.get_size());
+        if pos.0 - rad < max.0 && pos.0 + rad > min.0 && pos.1 - rad < max.1 && pos.1 + rad > min.1 {
+          handles.push(s.id.unwrap());
+        }
+      }
+    }
+    return handles;
+  }
+
+  /// Every splitter man whose centre is inside a polygon in world coords, in
+  /// handle order.
+  pub fn units_in_polygon(&self, poly: &[Vec2f32]) -> Vec<EHandle> {
+    let mut handles = Vec::new();
+    for e in &self.entity_list {
+      if let Entity::SplitterMan(s) = e.get() {
+        if point_in_polygon(s.body.pos, poly) { handles.push(s.id.unwrap()); }
+      }
+    }
+    return handles;
+  }
+
+  /// Every splitter man on screen the same size as `h`, in handle order.
+  fn on_screen_of_size(&self, h: EHandle) -> Vec<EHandle> {
+    let size = match self.entity_list.get(h) { Some(Entity::SplitterMan(s)) => s.size, _ => return Vec::new() };
+    let (w, h) = self.camera.screen_size();
+    let on_screen = self.units_in_box(self.camera.screen_to_world(0, 0), self.camera.screen_to_world(w, h));
+    return on_screen.into_iter().filter(|h| {
+      match self.entity_list.get(*h) { Some(Entity::SplitterMan(s)) => s.size == size, _ => false }
+    }).collect();
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use entity::SplitterMan;
+  use input::QueuedInput;
+  use level::Level;
+
+  fn engine() -> (Engine, Vec<EHandle>) {
+    let mut e = Engine::new_headless(800, 600, Box::new(QueuedInput::new()));
+    e.load_level(Level::parse("bounds 0 0 800 600\nspawn 100 300 4\n").unwrap());
+    e.camera.centre_on(Vec2f32(400.0, 300.0));
+    let mut handles = e.units_in_box(Vec2f32(0.0, 0.0), Vec2f32(800.0, 600.0));
+    // Overlapping the spawned one, and two small ones off on their own
+    handles.push(e.add_entity(Entity::SplitterMan(SplitterMan::new(115.0, 300.0, 4))));
+    handles.push(e.add_entity(Entity::SplitterMan(SplitterMan::new(400.0, 300.0, 1))));
+    handles.push(e.add_entity(Entity::SplitterMan(SplitterMan::new(600.0, 300.0, 1))));
+    return (e, handles);
+  }
+
+  #[test]
+  fn select_modes() {
+    let (mut e, h) = engine();
+    e.select(&[h[0], h[1]], SelectMode::Replace);
+    assert_eq!(e.selected(), vec![h[0], h[1]]);
+    e.select(&[h[2]], SelectMode::Replace);
+    assert_eq!(e.selected(), vec![h[2]]);
+    e.select(&[h[0]], SelectMode::Add);
+    assert_eq!(e.selected(), vec![h[0], h[2]]);
+    e.select(&[h[0], h[1]], SelectMode::Toggle);
+    assert_eq!(e.selected(), vec![h[1], h[2]]);
+    e.select(&[], SelectMode::Replace);
+    assert!(e.selected().is_empty());
+  }
+
+  #[test]
+  fn unit_at_picks_nearest_centre() {
+    let (e, h) = engine();
+    assert_eq!(e.unit_at(Vec2f32(95.0, 300.0)), Some(h[0]));
+    assert_eq!(e.unit_at(Vec2f32(110.0, 300.0)), Some(h[1]));
+    assert_eq!(e.unit_at(Vec2f32(405.0, 305.0)), Some(h[2]));
+    assert_eq!(e.unit_at(Vec2f32(250.0, 300.0)), None);
+  }
+
+  #[test]
+  fn double_click_selects_all_that_size() {
+    let (mut e, h) = engine();
+    let p = e.camera.world_to_screen(Vec2f32(400.0, 300.0));
+    let mut mode = SelectMode::Toggle;
+    assert_eq!(e.click(p, &mut mode), vec![h[2]]);
+    e.tick_count += DOUBLE_CLICK_TICKS;
+    assert_eq!(e.click(p, &mut mode), vec![h[2], h[3]]);
+    assert_eq!(mode, SelectMode::Add);
+    // Too slow
+    e.tick_count += DOUBLE_CLICK_TICKS + 1;
+    assert_eq!(e.click(p, &mut mode), vec![h[2]]);
+    e.tick_count += DOUBLE_CLICK_TICKS + 1;
+    assert_eq!(e.click(p, &mut mode), vec![h[2]]);
+  }
+
+  #[test]
+  fn no_double_click_after_loading_an_earlier_save() {
+    let (mut e, h) = engine();
+    let p = e.camera.world_to_screen(Vec2f32(400.0, 300.0));
+    let mut mode = SelectMode::Replace;
+    let mut saved = Vec::new();
+    e.write_save(&mut saved).unwrap();
+    e.tick_count = 100;
+    assert_eq!(e.click(p, &mut mode), vec![h[2]]);
+    e.read_save(&saved[..]).unwrap();
+    assert_eq!(e.click(p, &mut mode), vec![h[2]]);
+    // Even if the click wasn't forgotten
+    e.last_click = Some((h[2], 100));
+    assert_eq!(e.click(p, &mut mode), vec![h[2]]);
+  }
+}
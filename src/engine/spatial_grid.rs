@@ -0,0 +1,126 @@
+use std::collections::HashMap;
+use entity::EHandle;
+use engine::{EntityList, Vec2f32};
+
+/// Uniform grid of entity handles, bucketed by position. Entities are added to
+/// every cell their bounding box touches, so a query only has to look at the
+/// cells touched by the query's bounding box. Rebuilt by the engine at the
+/// start of every frame.
+pub struct SpatialGrid {
+  /// Width and height of each cell in world coords
+  cell_size: f32,
+  cells: HashMap<(i32, i32), Vec<EHandle>>,
+}
+
+impl SpatialGrid {
+  pub fn new(cell_size: f32) -> SpatialGrid {
+    SpatialGrid { cell_size, cells: HashMap::new() }
+  }
+
+  /// Get the range of cells (inclusive) covering a circle's bounding box.
+  fn cell_range(&self, pos: Vec2f32, rad: f32) -> ((i32, i32), (i32, i32)) {
+    let cell = |x: f32| (x / self.cell_size).floor() as i32;
+    return ((cell(pos.0 - rad), cell(pos.1 - rad)),
+            (cell(pos.0 + rad), cell(pos.1 + rad)));
+  }
+
+  /// Empty the grid and re-add every entity in the list.
+  pub fn rebuild(&mut self, list: &EntityList) {
+    // Drop cells which were empty last frame, and empty the rest, so we can
+    // reuse their allocations.
+    self.cells.retain(|_, v| !v.is_empty());
+    for v in self.cells.values_mut() { v.clear(); }
+    for e in list {
+      let e = e.get();
+      let body = e.get_body();
+      self.insert(e.get_entity_handle().unwrap(), body.pos, body.rad);
+    }
+  }
+
+  pub fn insert(&mut self, h: EHandle, pos: Vec2f32, rad: f32) {
+    let (min, max) = self.cell_range(pos, rad);
+    for x in min.0..max.0 + 1 {
+      for y in min.1..max.1 + 1 {
+        self.cells.entry((x, y)).or_default().push(h);
+      }
+    }
+  }
+
+  /// Find every entity whose bounding box touches the bounding box of the
+  /// given circle.
+  /// # Returns
+  /// The handles of the entities, sorted and without duplicates.
+  pub fn query(&self, pos: Vec2f32, rad: f32) -> Vec<EHandle> {
+    let (min, max) = self.cell_range(pos, rad);
+    let mut res = Vec::new();
+    for x in min.0..max.0 + 1 {
+      for y in min.1..max.1 + 1 {
+        if let Some(v) = self.cells.get(&(x, y)) { res.extend_from_slice(v); }
+      }
+    }
+    res.sort();
+    res.dedup();
+    return res;
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  /// Pseudo random circles, the same every run, over an area straddling the
+  /// origin so some have negative coords.
+  fn circles(n: u32) -> Vec<(EHandle, Vec2f32, f32)> {
+    let mut seed: u32 = 4321;
+    let mut rand = || {
+      seed = seed.wrapping_mul(1103515245).wrapping_add(12345);
+      (seed >> 8) as f32 / (1 << 24) as f32
+    };
+    (0..n).map(|ii| {
+      (EHandle(ii, 0), Vec2f32(rand() * 400.0 - 200.0, rand() * 400.0 - 200.0), rand() * 40.0)
+    }).collect()
+  }
+
+  fn boxes_touch(a: Vec2f32, a_rad: f32, b: Vec2f32, b_rad: f32) -> bool {
+    return (a.0 - b.0).abs() <= a_rad + b_rad && (a.1 - b.1).abs() <= a_rad + b_rad;
+  }
+
+  #[test]
+  fn query_finds_every_overlap() {
+    let mut grid = SpatialGrid::new(32.0);
+    let all = circles(300);
+    for &(h, pos, rad) in &all { grid.insert(h, pos, rad); }
+    for &(_, pos, rad) in &circles(100) {
+      let found = grid.query(pos, rad);
+      for &(h, p, r) in &all {
+        if boxes_touch(pos, rad, p, r) { assert!(found.contains(&h), "{:?} missed at {:?}", h, pos); }
+        // Anything else is at most a cell away
+        else if found.contains(&h) { assert!(boxes_touch(pos, rad + 32.0, p, r)); }
+      }
+    }
+  }
+
+  #[test]
+  fn circles_on_cell_edges() {
+    let mut grid = SpatialGrid::new(32.0);
+    // Straddling the origin, exactly on a cell corner, and on a negative cell
+    // edge
+    grid.insert(EHandle(0, 0), Vec2f32(0.0, 0.0), 1.0);
+    grid.insert(EHandle(1, 0), Vec2f32(64.0, 64.0), 0.0);
+    grid.insert(EHandle(2, 0), Vec2f32(-32.0, 10.0), 2.0);
+    assert_eq!(grid.query(Vec2f32(-5.0, -5.0), 1.0), vec![EHandle(0, 0)]);
+    assert_eq!(grid.query(Vec2f32(5.0, 5.0), 1.0), vec![EHandle(0, 0)]);
+    assert!(grid.query(Vec2f32(63.5, 63.5), 0.5).contains(&EHandle(1, 0)));
+    assert!(grid.query(Vec2f32(-33.0, 10.0), 0.5).contains(&EHandle(2, 0)));
+    assert!(grid.query(Vec2f32(-31.0, 10.0), 0.5).contains(&EHandle(2, 0)));
+  }
+
+  #[test]
+  fn results_sorted_without_duplicates() {
+    let mut grid = SpatialGrid::new(16.0);
+    // Inserted out of order, each covering lots of cells
+    for ii in &[5, 2, 9, 0, 7] { grid.insert(EHandle(*ii, 0), Vec2f32(*ii as f32, -3.0), 40.0); }
+    let found = grid.query(Vec2f32(0.0, 0.0), 50.0);
+    assert_eq!(found, [0, 2, 5, 7, 9].iter().map(|ii| EHandle(*ii, 0)).collect::<Vec<_>>());
+  }
+}
@@ -0,0 +1,207 @@
+use engine::Vec2f32;
+use engine::common::geometry::Shape;
+use entity::EntityBody;
+
+/// Extra space splitter men try to keep between each other, on top of their
+/// radii. Less than the gap between spots in a formation, so a group which
+/// has arrived doesn't keep pushing itself apart.
+const SEPARATION_MARGIN: f32 = 2.0;
+
+/// How strongly each steering behaviour pulls on a moving splitter man, and
+/// how quickly he can change velocity.
+#[derive(Clone, Copy, Debug)]
+pub struct SteeringWeights {
+  /// Heading for the next waypoint, or slowing down to stop on the target.
+  pub seek: f32,
+  /// Keeping away from splitter men we're overlapping.
+  pub separation: f32,
+  /// Turning away from obstacles and splitter men we're about to hit.
+  pub avoidance: f32,
+  /// Distance from the target to start slowing down at.
+  pub slowing_dist: f32,
+  /// How many frames ahead to look for things to avoid.
+  pub lookahead: f32,
+  /// Most the velocity can change by in one frame, as a fraction of top
+  /// speed.
+  pub max_accel: f32,
+}
+
+impl Default for SteeringWeights {
+  fn default() -> SteeringWeights { SteeringWeights::new() }
+}
+
+impl SteeringWeights {
+  pub fn new() -> SteeringWeights {
+    SteeringWeights {
+      seek: 1.0,
+      separation: 1.0,
+      avoidance: 1.0,
+      slowing_dist: 32.0,
+      lookahead: 8.0,
+      max_accel: 0.25,
+    }
+  }
+}
+
+/// Where a splitter man is steering to.
+#[derive(Clone, Copy, Debug)]
+pub enum Goal {
+  /// Go through the point at full speed, i.e. a waypoint on the way.
+  Seek(Vec2f32),
+  /// Slow down and stop exactly on the point.
+  Arrive(Vec2f32),
+  /// Slow down and stop wherever we are.
+  Stop,
+}
+
+/// Blend every steering behaviour into a new velocity.
+/// # Params
+/// * `body`   - The body of the splitter man being steered.
+/// * `speed`  - His top speed.
+/// * `others` - The bodies of nearby splitter men to keep away from.
+/// * `shapes` - Nearby obstacles which block him.
+/// # Returns
+/// The new velocity. When arriving, this never overshoots the target.
+pub fn steer(w: &SteeringWeights, body: &EntityBody, speed: f32, goal: Goal,
+             others: &[EntityBody], shapes: &[Shape]) -> Vec2f32 {
+  let mut desired = match goal {
+    Goal::Seek(p) => seek(body.pos, p, speed),
+    Goal::Arrive(p) => arrive(body.pos, p, speed, w.slowing_dist),
+    Goal::Stop => Vec2f32(0.0, 0.0),
+  };
+  desired.scale(w.seek);
+  desired += *separation(body, others).scale(w.separation * speed);
+  desired += *avoidance(body, others, shapes, w.lookahead).scale(w.avoidance * speed);
+  truncate(&mut desired, speed);
+
+  let mut change = desired - body.vel;
+  truncate(&mut change, speed * w.max_accel);
+  let mut vel = body.vel + change;
+  truncate(&mut vel, speed);
+  if let Goal::Arrive(p) = goal {
+    truncate(&mut vel, (p - body.pos).len());
+  }
+  return vel;
+}
+
+/// Velocity to head straight for a point at full speed.
+pub fn seek(pos: Vec2f32, p: Vec2f32, speed: f32) -> Vec2f32 {
+  let mut dir = p - pos;
+  if dir.len2() == 0.0 { return dir; }
+  return *dir.nor().scale(speed);
+}
+
+/// Velocity to head for a point, slowing down once within `slowing_dist` so
+/// we come to a stop on it.
+pub fn arrive(pos: Vec2f32, p: Vec2f32, speed: f32, slowing_dist: f32) -> Vec2f32 {
+  let dis = (p - pos).len();
+  let mut vel = seek(pos, p, speed);
+  if dis < slowing_dist { vel.scale(dis / slowing_dist); }
+  return vel;
+}
+
+/// Direction away from every splitter man we're too close to, weighted by
+/// how close they are. Each one adds at most 1 to the length.
+pub fn separation(body: &EntityBody, others: &[EntityBody]) -> Vec2f32 {
+  let mut force = Vec2f32(0.0, 0.0);
+  for o in others {
+    let mut away = body.pos - o.pos;
+    let dis = away.len();
+    let min_dis = body.rad + o.rad + SEPARATION_MARGIN;
+    if dis <= 0.0 || dis >= min_dis { continue; }
+    force += *away.nor().scale((min_dis - dis) / min_dis);
+  }
+  return force;
+}
+
+/// Direction to turn to avoid what we'll hit in the next `lookahead` frames
+/// at the current velocity. Obstacles push us out along their normal, so we
+/// slide along them. Splitter men ahead of us push us sideways, away from
+/// the side they're on, so two splitter men walking into each other both
+/// step aside.
+pub fn avoidance(body: &EntityBody, others: &[EntityBody], shapes: &[Shape], lookahead: f32) -> Vec2f32 {
+  let mut force = Vec2f32(0.0, 0.0);
+  let speed = body.vel.len();
+  if speed == 0.0 { return force; }
+  let ahead = *Vec2f32::new_from_copy(&body.vel).scale(lookahead);
+  let probe = body.pos + ahead;
+  for s in shapes {
+    if let Some(mut push) = s.push_out(probe, body.rad) {
+      let strength = (push.len() / body.rad).min(1.0);
+      force += *push.nor().scale(strength);
+    }
+  }
+  let range = ahead.len();
+  let dir = *Vec2f32::new_from_copy(&body.vel).scale(1.0 / speed);
+  let right = Vec2f32(-dir.1, dir.0);
+  for o in others {
+    let rel = o.pos - body.pos;
+    let along = rel.0*dir.0 + rel.1*dir.1;
+    if along <= 0.0 || along > range { continue; }
+    let side = rel.0*right.0 + rel.1*right.1;
+    let clearance = body.rad + o.rad;
+    if side.abs() >= clearance { continue; }
+    // Dead ahead, so pick a side. Always the same one, so both splitter men
+    // of a head on pair go different ways.
+    let away = if side > 0.0 { -1.0 } else { 1.0 };
+    let strength = (1.0 - along / range) * (1.0 - side.abs() / clearance);
+    force += *Vec2f32::new_from_copy(&right).scale(away * strength);
+  }
+  return force;
+}
+
+/// Shorten a vector so it's no longer than `max`.
+fn truncate(v: &mut Vec2f32, max: f32) {
+  let len = v.len();
+  if len > max && len > 0.0 { v.scale(max / len); }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn body(x: f32, y: f32) -> EntityBody {
+    EntityBody { pos: Vec2f32(x, y), vel: Vec2f32(0.0, 0.0), rad: 8.0 }
+  }
+
+  #[test]
+  fn arrive_slows_down_near_the_target() {
+    let far = arrive(Vec2f32(0.0, 0.0), Vec2f32(100.0, 0.0), 2.0, 32.0);
+    assert!((far.len() - 2.0).abs() < 0.001);
+    let near = arrive(Vec2f32(84.0, 0.0), Vec2f32(100.0, 0.0), 2.0, 32.0);
+    assert!((near.len() - 1.0).abs() < 0.001);
+    assert!(near.0 > 0.0);
+    assert_eq!(arrive(Vec2f32(5.0, 5.0), Vec2f32(5.0, 5.0), 2.0, 32.0).len(), 0.0);
+  }
+
+  #[test]
+  fn arriving_never_overshoots() {
+    let w = SteeringWeights::new();
+    let mut b = body(0.0, 0.0);
+    b.vel = Vec2f32(4.0, 0.0);
+    let target = Vec2f32(1.0, 0.0);
+    let vel = steer(&w, &b, 4.0, Goal::Arrive(target), &[], &[]);
+    assert!(vel.len() <= 1.0 + 0.001);
+    // And it settles on the target
+    for _ in 0..200 {
+      b.vel = steer(&w, &b, 4.0, Goal::Arrive(target), &[], &[]);
+      b.pos += b.vel;
+    }
+    assert!((b.pos - target).len() < 0.01);
+  }
+
+  #[test]
+  fn separation_pushes_apart() {
+    let me = body(0.0, 0.0);
+    // Overlapping on the right, so pushed left
+    let force = separation(&me, &[body(10.0, 0.0)]);
+    assert!(force.0 < 0.0 && force.1 == 0.0);
+    // Closer pushes harder
+    assert!(separation(&me, &[body(4.0, 0.0)]).len() > force.len());
+    // Far enough apart, or exactly on top, does nothing
+    assert_eq!(separation(&me, &[body(20.0, 0.0)]).len(), 0.0);
+    assert_eq!(separation(&me, &[body(0.0, 0.0)]).len(), 0.0);
+    // Pushes from both sides cancel out
+    assert_eq!(separation(&me, &[body(10.0, 0.0), body(-10.0, 0.0)]).len(), 0.0);
+  }
+}
@@ -1,23 +1,35 @@
 pub mod splitter_man;
+pub mod obstacle;
+pub mod pressure_plate;
 
 pub use self::splitter_man::SplitterMan;
+pub use self::obstacle::{Obstacle, ObstacleKind};
+pub use self::pressure_plate::PressurePlate;
 
 use game_renderer::RendererController;
 use engine::{Engine, Vec2f32};
 
-#[derive(Clone, Copy, Debug)]
-pub struct EHandle(pub u32);
+/// Handle to an entity in the engine's entity list. The first field is the
+/// slot index, the second is the slot's generation when the handle was made.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct EHandle(pub u32, pub u32);
 
 #[derive(Clone, Copy, Debug)]
 pub struct EntityBody {
   pub pos: Vec2f32, pub vel: Vec2f32, pub rad: f32,
 }
 
+// Entities are copied in and out of Cells, so the big splitter man variant
+// can't be boxed
+#[allow(clippy::large_enum_variant)]
 #[derive(Clone, Copy, Debug)]
 pub enum Entity {
   SplitterMan(SplitterMan),
+  Obstacle(Obstacle),
+  PressurePlate(PressurePlate),
 }
 
+#[allow(unused_macros)]
 macro_rules! empty {
   () => {}
 }
@@ -29,16 +41,18 @@ macro_rules! empty {
 /// * `$ent`    - The entity to match.
 /// * `$func`   - The identifier of the function to call (i.e. `update`)
 /// * `$b_rule` - The borrowing rules for the match arms - i.e. ref mut or ref. 
-///               Needs to be surrounded by [], so [ref mut], or [ref].
+///   Needs to be surrounded by [], so [ref mut], or [ref].
 /// * `$params` - A list of optional parameters to pass to the method.
 /// ### Example
-/// ```
+/// ```ignore
 /// entity_match_and_run!(*self, update, [ref mut], engine);
 /// ```
 macro_rules! entity_match_and_run {
   ( $ent: expr, $func: ident, [$( $b_rule:tt )*] $(,$arg:tt)* ) => (
     match $ent {
-      Entity::SplitterMan($($b_rule)* e) => e.$func($($arg)*)
+      Entity::SplitterMan($($b_rule)* e) => e.$func($($arg)*),
+      Entity::Obstacle($($b_rule)* e) => e.$func($($arg)*),
+      Entity::PressurePlate($($b_rule)* e) => e.$func($($arg)*),
     }
   );
 }
@@ -69,6 +83,13 @@ impl Entity {
     entity_match_and_run!(*self, get_body, [ref])
   }
 
+  /// Returns the mass used when pushing overlapping entities apart, or None if
+  /// this entity should pass through other entities. Obstacles stop
+  /// everything either way.
+  pub fn get_mass(&self) -> Option<f32> {
+    entity_match_and_run!(*self, get_mass, [ref])
+  }
+
   pub fn set_pos(&mut self, pos: Vec2f32) {
     entity_match_and_run!(*self, set_pos, [ref mut], pos)
   }
@@ -0,0 +1,84 @@
+use game_renderer::RendererController;
+use engine::{Engine, Vec2f32};
+use engine::common::geometry::Shape;
+use entity::{Entity, EHandle, EntityBody};
+
+/// What an obstacle lets through.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ObstacleKind {
+  /// Solid to everything.
+  Wall,
+  /// Only splitter men with a radius below this can pass through.
+  Gap(f32),
+  /// Solid unless every pressure plate on the channel is pressed. `open` is
+  /// updated every frame.
+  Door { channel: u32, open: bool },
+}
+
+/// An immovable shape. Splitter men are pushed out of obstacles which block
+/// them, so they slide along them rather than passing through.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Obstacle {
+  pub id: Option<EHandle>,
+  pub shape: Shape,
+  pub kind: ObstacleKind,
+}
+
+impl Obstacle {
+  pub fn new(shape: Shape, kind: ObstacleKind) -> Obstacle {
+    Obstacle { id: None, shape, kind }
+  }
+
+  /// Whether this obstacle currently stops a splitter man with the given
+  /// radius.
+  pub fn blocks(&self, rad: f32) -> bool {
+    match self.kind {
+      ObstacleKind::Wall => true,
+      ObstacleKind::Gap(max_rad) => rad >= max_rad,
+      ObstacleKind::Door { open, .. } => !open,
+    }
+  }
+
+  /// Doors open and close with their pressure plates, everything else does
+  /// nothing on its own.
+  pub fn update(&mut self, engine: &Engine) -> (bool, Option<Vec<Entity>>, Vec2f32) {
+    if let ObstacleKind::Door { channel, ref mut open } = self.kind {
+      *open = engine.channel_active(channel);
+    }
+    (false, None, self.shape.centre())
+  }
+
+  /// R, G, B, A to draw this obstacle with.
+  pub fn colour(&self) -> (f32, f32, f32, f32) {
+    match self.kind {
+      ObstacleKind::Wall => (0.4, 0.4, 0.45, 1.0),
+      ObstacleKind::Gap(_) => (0.3, 0.3, 0.6, 0.6),
+      ObstacleKind::Door { open: false, .. } => (0.6, 0.4, 0.2, 1.0),
+      ObstacleKind::Door { open: true, .. } => (0.6, 0.4, 0.2, 0.2),
+    }
+  }
+
+  pub fn render(&self, cont: &mut RendererController) {
+    let c = self.colour();
+    match self.shape {
+      Shape::Rect(ref r) => cont.rect(r.pos.0, r.pos.1, r.size.0, r.size.1, c.0, c.1, c.2, c.3),
+      Shape::Poly(ref p) => cont.poly(p.verts(), c.0, c.1, c.2, c.3),
+    }
+  }
+
+  pub fn get_id(&self) -> Option<EHandle> { self.id }
+  pub fn set_id(&mut self, new_handle: EHandle) { self.id = Some(new_handle) }
+
+  /// The body is a circle around the whole shape, so it's only good for
+  /// broadphase.
+  pub fn get_body(&self) -> EntityBody {
+    EntityBody { pos: self.shape.centre(), vel: Vec2f32(0.0, 0.0), rad: self.shape.bounding_rad() }
+  }
+
+  /// Obstacles never move.
+  pub fn set_pos(&mut self, _: Vec2f32) {}
+
+  /// Obstacles aren't circles, so don't take part in circle collisions. The
+  /// engine handles them separately.
+  pub fn get_mass(&self) -> Option<f32> { None }
+}
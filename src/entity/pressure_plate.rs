@@ -0,0 +1,51 @@
+use game_renderer::RendererController;
+use engine::{Engine, Vec2f32};
+use engine::common::geometry::Rect;
+use entity::{Entity, EHandle, EntityBody};
+
+/// Area of floor which is pressed when the splitter men standing on it add
+/// up to a big enough size. Doors on the same channel open when every plate
+/// on the channel is pressed.
+#[derive(Clone, Copy, Debug)]
+pub struct PressurePlate {
+  pub id: Option<EHandle>,
+  pub area: Rect,
+  /// Total size of splitter men needed to press the plate
+  pub threshold: u32,
+  pub channel: u32,
+  /// Updated every frame, just used for rendering. Use
+  /// Engine::channel_active to check the plate.
+  pub pressed: bool,
+}
+
+impl PressurePlate {
+  pub fn new(area: Rect, threshold: u32, channel: u32) -> PressurePlate {
+    PressurePlate { id: None, area, threshold, channel, pressed: false }
+  }
+
+  pub fn update(&mut self, engine: &Engine) -> (bool, Option<Vec<Entity>>, Vec2f32) {
+    self.pressed = engine.load_on(&self.area) >= self.threshold;
+    (false, None, self.area.centre())
+  }
+
+  pub fn render(&self, cont: &mut RendererController) {
+    let c = if self.pressed { (1.0, 0.9, 0.2, 0.8) } else { (0.5, 0.45, 0.1, 0.5) };
+    let a = &self.area;
+    cont.rect(a.pos.0, a.pos.1, a.size.0, a.size.1, c.0, c.1, c.2, c.3);
+  }
+
+  pub fn get_id(&self) -> Option<EHandle> { self.id }
+  pub fn set_id(&mut self, new_handle: EHandle) { self.id = Some(new_handle) }
+
+  /// The body is a circle around the whole plate, so it's only good for
+  /// broadphase.
+  pub fn get_body(&self) -> EntityBody {
+    EntityBody { pos: self.area.centre(), vel: Vec2f32(0.0, 0.0), rad: (self.area.max() - self.area.centre()).len() }
+  }
+
+  /// Pressure plates never move.
+  pub fn set_pos(&mut self, _: Vec2f32) {}
+
+  /// Pressure plates are walked over, so never collide.
+  pub fn get_mass(&self) -> Option<f32> { None }
+}
@@ -1,10 +1,35 @@
 use game_renderer::RendererController;
-use input;
 use engine::{Vec2f32, Engine};
+use engine::nav::Path;
+use engine::steering::{self, Goal};
+use std::collections::BTreeMap;
+use engine::EntityList;
 use entity::{Entity, EHandle, EntityBody};
 
 pub mod state;
-pub use self::state::{State, IdleState, MovingState, SplittingState, JoiningState};
+pub mod queue;
+
+/// How close to the target a splitter man has to get to have arrived.
+const ARRIVE_DIST: f32 = 0.5;
+
+pub use self::state::{State, IdleState, MovingState, SplittingState, JoiningState,
+                      HoldingState, PatrollingState};
+pub use self::queue::{MoveQueue, QueuedMove};
+
+/// Splitter men given the same join order, found once a frame by the engine
+/// with SplitterMan::find_join_groups.
+#[derive(Clone, Debug)]
+pub struct JoinGroup {
+  /// Size-weighted centre of the group.
+  pub centre: Vec2f32,
+  /// Total size of the group.
+  pub size: u32,
+  /// Handles of the group, in handle order.
+  pub members: Vec<EHandle>,
+  /// Whether every member overlaps the centre, i.e. the group is ready to
+  /// merge.
+  pub ready: bool,
+}
 
 #[derive(Clone, Copy, Debug)]
 pub struct SplitterMan {
@@ -17,6 +42,17 @@ pub struct SplitterMan {
   /// The splitter man's target location in world coordinates
   pub target: Option<Vec2f32>,
 
+  /// Where the player clicked to move the group this splitter man is part
+  /// of, if the group is big enough to share a flow field. The target is
+  /// this splitter man's spot in the group's formation.
+  pub group_target: Option<Vec2f32>,
+
+  /// Waypoints to the target. None if a path needs finding.
+  pub path: Option<Path>,
+
+  /// Moves to make once we reach the target.
+  pub queue: MoveQueue,
+
   /// Splitter man's 'split level'. i.e. how many clones he can split into.
   /// Lowest value = 1.
   pub size: u32,
@@ -24,6 +60,9 @@ pub struct SplitterMan {
   /// Has the player selected this splitter man?
   pub selected: bool,
 
+  /// Control groups this splitter man is in, one bit per group.
+  pub groups: u16,
+
   pub speed: f32,
 }
 
@@ -36,114 +75,224 @@ impl SplitterMan {
         rad: SplitterMan::calc_size(size),
       },
       state: State::Idle(IdleState::new()),
-      size: size, 
+      size, 
       target: None, 
+      group_target: None,
+      path: None,
+      queue: MoveQueue::new(),
       selected: false, 
-      speed: (64.0 - size as f32).sqrt() * 0.15 + 2.0 ,
+      groups: 0,
+      speed: (64.0 - size as f32).max(0.0).sqrt() * 0.15 + 2.0 ,
       id: None,
     }
   }
 
-  /// Processes collisions. Returns the new position of the entity, according to collisions.
-  fn process_coll(&mut self, e: &Engine) -> Vec2f32 {
-    let new_pos = self.body.pos + self.body.vel;
-    let mut final_pos = new_pos;
-    for e in &e.entity_list {
-      let e = e.get();
-      if e.get_entity_handle().unwrap().0 == self.id.unwrap().0  { 
-        continue; 
-      }
-      let body = e.get_body();
-      let (pos, rad) = (body.pos, body.rad);
-      let dis = (new_pos - pos).len();
-      let overlap = self.body.rad + rad - dis;
-      if overlap > 0.0 {
-        // Adjust position accordingly half way (assume other entity will do
-        // the same, to result in a perfect resolution)
-        // Can optimise, we've already calculated distance but we're doing it again (.nor())
-        final_pos = self.body.pos + *(pos - new_pos).nor().scale(-overlap/2.0);
-      }
+  /// Steer along the path to the target, finding a path first if we don't
+  /// have one. Big groups share a flow field to where they were sent,
+  /// everyone else uses A*. If the target can't be reached, stops at the end
+  /// of the path, which is as close as we can get.
+  /// # Returns
+  /// True if we've arrived at the target.
+  fn follow_path(&mut self, e: &Engine) -> bool {
+    if self.target.is_none() { return false; }
+    let t = self.target.unwrap();
+    if self.path.is_none() {
+      let points = match self.group_target {
+        Some(g) => e.find_group_path(self.body.pos, t, g, self.body.rad),
+        None => e.find_path(self.body.pos, t, self.body.rad),
+      };
+      // No level to find a path around, so go straight there
+      let points = points.unwrap_or_else(|| vec![t]);
+      self.path = Some(Path::new(&points));
+    }
+    let mut path = self.path.unwrap();
+    let at_end = path.complete && path.next_index() + 1 == path.points().len();
+    // Waypoints on the way just need to be touched, since other splitter men
+    // following the same path will be crowding them
+    let reach = self.body.rad.max(self.speed);
+    if let Some(p) = path.next_point() {
+      if !at_end && (p - self.body.pos).len2() < reach*reach { path.advance(); }
     }
-    return final_pos;
+    // Reached the end of a path too long to store all of, so find the rest
+    // of it next frame
+    if path.next_point().is_none() && !path.complete { self.path = None; }
+    else { self.path = Some(path); }
+    let at_end = path.complete && path.next_index() + 1 == path.points().len();
+    // Don't stop at the target if there's another move queued after it
+    let passing = !self.queue.is_empty();
+    let goal = match path.next_point() {
+      Some(p) if at_end && !passing => Goal::Arrive(p),
+      Some(p) => Goal::Seek(p),
+      None => Goal::Stop,
+    };
+    self.steer(e, goal);
+    return match goal {
+      Goal::Arrive(p) => p == t && (t - (self.body.pos + self.body.vel)).len2() < ARRIVE_DIST*ARRIVE_DIST,
+      Goal::Seek(p) if at_end => p == t && (t - self.body.pos).len2() < reach*reach,
+      _ => false,
+    };
   }
 
-  /// Check if the box select has his this entity.
+  /// Start on the next queued move.
   /// # Returns
-  /// True if this entity was selected
-  fn check_selection_box(&mut self, e: &Engine) -> bool {
-    if e.input_handler.selection.is_some() {
-      // Test for collision between input rect and splitterman rect
-      let rad = self.get_size();
-      let mut sel = e.input_handler.selection.unwrap();
-      // Make sure sel isn't malformed (sel[0], sel[1] is the top left)
-      if sel[0].0 > sel[1].0 { let tmp = sel[1].0; sel[1].0 = sel[0].0; sel[0].0 = tmp }
-      if sel[0].1 > sel[1].1 { let tmp = sel[1].1; sel[1].1 = sel[0].1; sel[0].1 = tmp }
-      // get selection as world coords
-      let sel_0 = e.g_renderer.camera.screen_to_world(sel[0].0 as i32, sel[0].1 as i32);
-      let sel_1 = e.g_renderer.camera.screen_to_world(sel[1].0 as i32, sel[1].1 as i32);
-      if self.body.pos.0 - rad < sel_1.0
-        && self.body.pos.0 + rad > sel_0.0
-          && self.body.pos.1 - rad < sel_1.1
-          && self.body.pos.1 + rad > sel_0.1 {
-            self.selected = true;
-            return true;
-          }
-      else {
-        self.selected = false;
+  /// False if there are no moves queued.
+  fn next_move(&mut self) -> bool {
+    match self.queue.pop() {
+      Some(m) => {
+        self.target = Some(m.target);
+        self.group_target = m.group_target;
+        self.path = None;
+        return true;
+      },
+      None => return false,
+    }
+  }
+
+  /// Set our velocity with the engine's steering behaviours, keeping away
+  /// from nearby splitter men and obstacles.
+  fn steer(&mut self, e: &Engine, goal: Goal) {
+    let look = self.speed * e.steering.lookahead;
+    let mut others = Vec::new();
+    let mut shapes = Vec::new();
+    for h in e.neighbours(self.body.pos, self.body.rad + look) {
+      if Some(h) == self.id { continue; }
+      match e.entity_list.get(h) {
+        Some(Entity::SplitterMan(s)) if s.get_mass().is_some() => others.push(s.body),
+        Some(Entity::Obstacle(o)) if o.blocks(self.body.rad) => shapes.push(o.shape),
+        _ => (),
       }
     }
-    return false;
+    self.body.vel = steering::steer(&e.steering, &self.body, self.speed, goal, &others, &shapes);
+  }
+
+  /// Stop moving and forget where we were going.
+  fn stop(&mut self) {
+    self.target = None;
+    self.group_target = None;
+    self.path = None;
+    self.queue.clear();
+    self.body.vel = Vec2f32(0.0, 0.0);
+  }
+
+  /// Whether we'll do as we're told. Splitting splitter men are busy until
+  /// they've finished. Joining ones can be given another order instead.
+  pub fn can_take_orders(&self) -> bool {
+    return !matches!(self.state, State::Splitting(_));
   }
 
-  /// Get the velocity to move towards the target
-  fn set_vel_to_target(&mut self) {
-    if self.target.is_some() {
-      let t = self.target.unwrap();
-      let mut dir = t - self.body.pos;
-      if dir.len2() < self.speed*self.speed {
-        self.body.pos = self.target.unwrap();
-        self.body.vel = Vec2f32(0.0, 0.0);
+  /// Split in two, if we're big enough to.
+  /// # Returns
+  /// None if we can't split any further. Otherwise whether this splitter man
+  /// should be removed, and the two halves to add to the entity list. If
+  /// split_ticks is more than 0, the halves are spawned in the splitting
+  /// state, and this splitter man shrinks away in the splitting state rather
+  /// than being removed straight away.
+  pub fn split(&mut self, split_ticks: u32) -> Option<(bool, Vec<Entity>)> {
+    if self.size <= 1 { return None; }
+    let next_size = self.size / 2;
+    let rad = SplitterMan::calc_size(next_size);
+    let pos = self.body.pos;
+    let offsets = [Vec2f32(-rad, 0.0), Vec2f32(rad, 0.0)];
+    let mut children = Vec::new();
+    for off in &offsets {
+      let mut child;
+      if split_ticks == 0 {
+        child = SplitterMan::new(pos.0 + off.0, pos.1 + off.1, next_size);
       }
       else {
-        self.body.vel = *dir.nor().scale(self.speed);
+        child = SplitterMan::new(pos.0, pos.1, next_size);
+        child.body.rad = 0.0;
+        child.state = State::Splitting(SplittingState::new(
+            split_ticks, pos, pos + *off, 0.0, rad));
       }
+      child.selected = self.selected;
+      child.groups = self.groups;
+      children.push(Entity::SplitterMan(child));
     }
+    if split_ticks == 0 { return Some((true, children)); }
+    self.stop();
+    self.state = State::Splitting(SplittingState::new(
+        split_ticks, pos, pos, self.body.rad, 0.0));
+    return Some((false, children));
+  }
+
+  /// Start finding our way to the other splitter men given the same join
+  /// order to merge with them. `leader` is the handle of the group's lowest handled
+  /// member, which identifies the group.
+  pub fn join(&mut self, leader: EHandle) {
+    self.stop();
+    self.state = State::Joining(JoiningState::new(leader));
   }
 
-  /// Checks if the player has pressed the split control, and whether this
-  /// entity can split any further.. If so, splits this entity. 
+  /// Stop where we are and forget every order.
+  pub fn order_stop(&mut self) {
+    self.stop();
+    self.state = State::Idle(IdleState::new());
+  }
+
+  /// Stop where we are and stay there, without being shoved.
+  pub fn hold(&mut self) {
+    self.stop();
+    self.state = State::Holding(HoldingState::new());
+  }
+
+  /// Walk to each point in turn, going back to the first after the last.
+  /// Points past the size of the move queue are dropped.
+  pub fn patrol(&mut self, points: &[QueuedMove]) {
+    if points.is_empty() { return; }
+    self.stop();
+    self.target = Some(points[0].target);
+    self.group_target = points[0].group_target;
+    for m in &points[1..] { self.queue.push(*m); }
+    self.state = State::Patrolling(PatrollingState::new());
+  }
+
+  /// Find every group of splitter men joining together, in one pass over
+  /// the entity list.
   /// # Returns
-  /// The standard return value to notify whether this entity should be removed
-  /// at the end of the update, and to add the split children to the entity
-  /// list (if this entity splits).
-  fn check_for_split(&mut self, e: &Engine) -> (bool, Option<Vec<Entity>>) {
-    if self.size > 1 && e.input_handler.inputs.get(&input::Control::Split).unwrap().just_down {
-      let next_size = self.size / 2;
-      let rad = SplitterMan::calc_size(next_size);
-      let mut child_1 = SplitterMan::new(self.body.pos.0 - rad, self.body.pos.1, next_size);
-      let mut child_2 = SplitterMan::new(self.body.pos.0 + rad, self.body.pos.1, next_size);
-      child_1.selected = true;
-      child_2.selected = true;
-      return (true, Some(vec![
-                         Entity::SplitterMan(child_1),
-                         Entity::SplitterMan(child_2)]));
+  /// The groups, keyed by their leader's handle.
+  pub fn find_join_groups(list: &EntityList) -> BTreeMap<EHandle, JoinGroup> {
+    let mut groups: BTreeMap<EHandle, JoinGroup> = BTreeMap::new();
+    let mut members: BTreeMap<EHandle, Vec<SplitterMan>> = BTreeMap::new();
+    for ent in list {
+      if let Entity::SplitterMan(s) = ent.get() {
+        if let State::Joining(st) = s.state { members.entry(st.leader).or_default().push(s); }
+      }
     }
-    return (false, None);
+    for (leader, members) in members {
+      let mut centre = Vec2f32(0.0, 0.0);
+      let mut total_size = 0;
+      for s in &members {
+        centre += *Vec2f32::new_from_copy(&s.body.pos).scale(s.size as f32);
+        total_size += s.size;
+      }
+      if total_size > 0 { centre.scale(1.0 / total_size as f32); }
+      let ready = members.iter().all(|s| (s.body.pos - centre).len() <= s.body.rad);
+      let handles = members.iter().map(|s| s.id.unwrap()).collect();
+      groups.insert(leader, JoinGroup { centre, size: total_size, members: handles, ready });
+    }
+    return groups;
   }
 
-  /// Checks for if the player has set a target dest for this entity.
-  /// # Returns
-  /// True if the player has moved this entity. False otherwise.
-  fn check_for_move(&mut self, e: &Engine) -> bool {
-    if self.selected {
-      if e.input_handler.inputs.get(&input::Control::Move).unwrap().down
-        && !e.input_handler.inputs.get(&input::Control::Select).unwrap().down {
-          // Set target
-          self.target = Some(e.g_renderer.camera.screen_to_world(e.input_handler.mouse_pos.0, e.input_handler.mouse_pos.1));
-          return true;
-        }
+  /// Head for `t`, one spot of a group moving to `group_target` if the group
+  /// is big enough to share a flow field. If `queue` is set and we're
+  /// already moving, `t` is visited after the moves we've already been
+  /// given, otherwise it replaces them.
+  pub fn order_move(&mut self, t: Vec2f32, group_target: Option<Vec2f32>, queue: bool) {
+    let moving = matches!(self.state, State::Moving(_));
+    if queue && moving {
+      self.queue.push(QueuedMove { target: t, group_target });
+      return;
     }
-    return false;
+    if moving { self.queue.clear(); }
+    else {
+      self.stop();
+      self.state = State::Moving(MovingState::new());
+    }
+    // Set target, finding a new path if it's moved
+    if self.target != Some(t) { self.path = None; }
+    self.target = Some(t);
+    self.group_target = group_target;
   }
 
   /// Process this entity's current state object. Can change state if required.
@@ -151,8 +300,7 @@ impl SplitterMan {
   fn process_state(&mut self, e: &Engine) -> (bool, Option<Vec<Entity>>, Vec2f32) {
     let mut state_copy = self.state;
     let (next_state, ret) = state_copy.process(self, e);
-    if next_state.is_some() { self.state = next_state.unwrap(); }
-    else { self.state = state_copy; }
+    self.state = next_state.unwrap_or(state_copy);
     return ret;
   }
 
@@ -164,14 +312,35 @@ impl SplitterMan {
     // Process state machine
     let (remove, ents, new_pos) = self.process_state(e);
 
-    // Process movement, if movement was not already ordered from the state machine
-    let final_pos;
-    if new_pos == self.body.pos { final_pos = self.process_coll(e); }
-    else { final_pos = new_pos; }
+    // Process movement, if movement was not already ordered from the state
+    // machine. Collisions are resolved by the engine after every entity has
+    // moved.
+    let final_pos = if new_pos == self.body.pos { self.body.pos + self.body.vel } else { new_pos };
 
     return (remove, ents, final_pos);
   }
 
+  /// Mass for collision resolution, so bigger splitter men shove smaller
+  /// ones. Joining and splitting splitter men need to overlap, so they pass
+  /// through each other, but not through obstacles. Holding splitter men
+  /// can't be shoved by other splitter men, though obstacles like closing
+  /// doors still push them out.
+  pub fn get_mass(&self) -> Option<f32> {
+    if matches!(self.state, State::Joining(_) | State::Splitting(_)) { return None; }
+    if let State::Holding(_) = self.state { return Some(f32::INFINITY); }
+    return Some(self.size as f32);
+  }
+
+  /// How much this splitter man weighs on pressure plates. A splitter man
+  /// splitting in two weighs nothing, as his children already weigh his size
+  /// between them.
+  pub fn load(&self) -> u32 {
+    if let State::Splitting(st) = self.state {
+      if st.is_parent() { return 0; }
+    }
+    return self.size;
+  }
+
   /// Get entity's visual size (radius)
   #[inline(always)]
   pub fn get_size(&self) -> f32 { self.body.rad }
@@ -180,16 +349,29 @@ impl SplitterMan {
   #[inline(always)]
   pub fn calc_size(size: u32) -> f32 { (size as f32).sqrt() * 8.0 }
 
+  /// R, G, B, A to draw this splitter man with.
+  pub fn colour(&self) -> (f32, f32, f32, f32) {
+    if self.selected { (0.0, 1.0, 1.0, 1.0) }
+    else { (1.0, 0.0, 0.0, 1.0) }
+  }
+
   pub fn render(&self, cont: &mut RendererController) {
     let rad = self.get_size();
-    let c : (f32, f32, f32, f32);
-    if self.selected {
-      c = (0.0, 1.0, 1.0, 1.0);
-    }
-    else {
-      c = (1.0, 0.0, 0.0, 1.0);
-    }
+    let c = self.colour();
     cont.rect(self.body.pos.0 - rad, self.body.pos.1 - rad, rad*2.0, rad*2.0, c.0, c.1, c.2, c.3);
+    // Show where we're going, and every move queued after that
+    if let (true, Some(target)) = (self.selected, self.target) {
+      let mut from = self.body.pos;
+      let targets = Some(target).into_iter().chain(self.queue.moves().iter().map(|m| m.target));
+      for t in targets {
+        cont.line(from, t, 2.0, 0.0, 1.0, 1.0, 0.4);
+        from = t;
+      }
+      // Patrols loop back round
+      if let State::Patrolling(_) = self.state {
+        cont.line(from, target, 2.0, 0.0, 1.0, 1.0, 0.4);
+      }
+    }
   }
 
   pub fn get_id(&self) -> Option<EHandle> { self.id }
@@ -0,0 +1,101 @@
+use engine::Vec2f32;
+
+/// Most move orders a splitter man can have queued up after his current one.
+/// Any more are dropped.
+pub const MAX_QUEUED_MOVES: usize = 16;
+
+/// A move order waiting for the ones before it to finish.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct QueuedMove {
+  pub target: Vec2f32,
+  /// Where the player clicked, if the order was given to a group big enough
+  /// to share a flow field. See SplitterMan::group_target.
+  pub group_target: Option<Vec2f32>,
+}
+
+/// Move orders to carry out once the current one is done, in order. Stored
+/// inline so splitter men stay Copy.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct MoveQueue {
+  moves: [QueuedMove; MAX_QUEUED_MOVES],
+  len: usize,
+}
+
+impl Default for MoveQueue {
+  fn default() -> MoveQueue { MoveQueue::new() }
+}
+
+impl MoveQueue {
+  pub fn new() -> MoveQueue {
+    let empty = QueuedMove { target: Vec2f32(0.0, 0.0), group_target: None };
+    MoveQueue { moves: [empty; MAX_QUEUED_MOVES], len: 0 }
+  }
+
+  /// Add a move to the end of the queue.
+  /// # Returns
+  /// False if the queue is full, so the move was dropped.
+  pub fn push(&mut self, m: QueuedMove) -> bool {
+    if self.len >= MAX_QUEUED_MOVES { return false; }
+    self.moves[self.len] = m;
+    self.len += 1;
+    return true;
+  }
+
+  /// Take the move at the front of the queue.
+  pub fn pop(&mut self) -> Option<QueuedMove> {
+    if self.len == 0 { return None; }
+    let m = self.moves[0];
+    // Shift along rather than wrapping around, so queues with the same moves
+    // are always stored (and saved) the same way
+    self.moves.copy_within(1..self.len, 0);
+    self.len -= 1;
+    self.moves[self.len] = QueuedMove { target: Vec2f32(0.0, 0.0), group_target: None };
+    return Some(m);
+  }
+
+  pub fn clear(&mut self) { *self = MoveQueue::new(); }
+
+  /// Every queued move, front first.
+  pub fn moves(&self) -> &[QueuedMove] { &self.moves[..self.len] }
+
+  pub fn len(&self) -> usize { self.len }
+
+  pub fn is_empty(&self) -> bool { self.len == 0 }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn to(x: f32) -> QueuedMove { QueuedMove { target: Vec2f32(x, 0.0), group_target: None } }
+
+  #[test]
+  fn moves_come_out_in_order() {
+    let mut q = MoveQueue::new();
+    assert_eq!(q.pop(), None);
+    for ii in 0..3 { assert!(q.push(to(ii as f32))); }
+    assert_eq!(q.moves(), &[to(0.0), to(1.0), to(2.0)]);
+    assert_eq!(q.pop(), Some(to(0.0)));
+    q.push(to(3.0));
+    assert_eq!(q.pop(), Some(to(1.0)));
+    assert_eq!(q.pop(), Some(to(2.0)));
+    assert_eq!(q.pop(), Some(to(3.0)));
+    assert_eq!(q.pop(), None);
+    // Emptied by popping looks the same as new, so they save the same
+    assert_eq!(q, MoveQueue::new());
+  }
+
+  #[test]
+  fn full_queue_drops_moves() {
+    let mut q = MoveQueue::new();
+    for ii in 0..MAX_QUEUED_MOVES { assert!(q.push(to(ii as f32))); }
+    assert!(!q.push(to(99.0)));
+    assert_eq!(q.len(), MAX_QUEUED_MOVES);
+    assert_eq!(q.moves()[MAX_QUEUED_MOVES - 1], to((MAX_QUEUED_MOVES - 1) as f32));
+    // Making room lets moves in again
+    q.pop();
+    assert!(q.push(to(99.0)));
+    q.clear();
+    assert!(q.is_empty());
+  }
+}
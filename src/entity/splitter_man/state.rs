@@ -1,70 +1,183 @@
-use entity::{Entity, SplitterMan};
+use entity::{Entity, EHandle, SplitterMan};
+use entity::splitter_man::QueuedMove;
 use engine::{Engine, Vec2f32};
 
-#[derive(Copy, Clone, Debug)]
+/// What processing a state gives back: the state to change to, if any, and
+/// what to return from update().
+pub type Processed = (Option<State>, (bool, Option<Vec<Entity>>, Vec2f32));
+
+#[derive(Copy, Clone, Debug, Default)]
 pub struct IdleState;
 impl IdleState {
   pub fn new() -> IdleState { IdleState }
   fn process(&mut self, 
              e: &mut SplitterMan, 
-             engine: &Engine) -> (Option<State>, (bool, Option<Vec<Entity>>, Vec2f32)) {
-    let (mut remove, mut ents, new_pos) = (false, None, e.body.pos);
-    let mut next_state = None;
-
-    e.check_selection_box(engine);
-    if e.selected {
-      let res = e.check_for_split(engine);
-      remove = res.0;
-      ents = res.1;
-      let moved = e.check_for_move(engine);
-      if moved { next_state = Some(State::Moving(MovingState::new())); }
-    }
-    return (next_state, (remove, ents, new_pos));
+             _: &Engine) -> Processed {
+    return (None, (false, None, e.body.pos));
   }
 }
 
-#[derive(Copy, Clone, Debug)]
+#[derive(Copy, Clone, Debug, Default)]
 pub struct MovingState;
 impl MovingState {
   pub fn new() -> MovingState { MovingState }
   fn process(&mut self, 
              e: &mut SplitterMan, 
-             engine: &Engine) -> (Option<State>, (bool, Option<Vec<Entity>>, Vec2f32)) {
-    let (mut remove, mut ents, new_pos) = (false, None, e.body.pos);
-    let next_state = None;
-
-    e.check_selection_box(engine);
-    if e.selected {
-      let res = e.check_for_split(engine);
-      remove = res.0;
-      ents = res.1;
-      e.check_for_move(engine);
+             engine: &Engine) -> Processed {
+    let new_pos = e.body.pos;
+    if e.follow_path(engine) {
+      // Arrived, so carry on with the next queued move, or if there isn't
+      // one, finish exactly on the target
+      if e.next_move() { return (None, (false, None, new_pos)); }
+      let t = e.target.unwrap();
+      e.stop();
+      return (Some(State::Idle(IdleState::new())), (false, None, t));
     }
-    e.set_vel_to_target();
+    return (None, (false, None, new_pos));
+  }
+}
 
-    return (next_state, (remove, ents, new_pos));
+/// Standing still and refusing to be pushed around, until given another
+/// order.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct HoldingState;
+impl HoldingState {
+  pub fn new() -> HoldingState { HoldingState }
+  fn process(&mut self, 
+             e: &mut SplitterMan, 
+             _: &Engine) -> Processed {
+    return (None, (false, None, e.body.pos));
   }
 }
 
+/// Walking between points forever. The point being walked to is the target,
+/// and the rest are queued. Each point is put back on the end of the queue
+/// once it's reached.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct PatrollingState;
+impl PatrollingState {
+  pub fn new() -> PatrollingState { PatrollingState }
+  fn process(&mut self, 
+             e: &mut SplitterMan, 
+             engine: &Engine) -> Processed {
+    if e.follow_path(engine) {
+      e.queue.push(QueuedMove { target: e.target.unwrap(), group_target: e.group_target });
+      e.next_move();
+    }
+    return (None, (false, None, e.body.pos));
+  }
+}
+
+/// Animating a split. Both the parent and the children of a split are in this
+/// state - the parent shrinks away and is removed at the end, and the
+/// children grow and slide apart, then go idle. Orders are ignored until the
+/// split is finished.
 #[derive(Copy, Clone, Debug)]
-pub struct SplittingState;
+pub struct SplittingState {
+  /// Frames spent in this state so far
+  pub tick: u32,
+  /// Frames the split takes
+  pub duration: u32,
+  /// Position at the start and end of the split
+  pub from: Vec2f32,
+  pub to: Vec2f32,
+  /// Radius at the start and end of the split. The parent ends with a radius
+  /// of 0.
+  pub from_rad: f32,
+  pub to_rad: f32,
+}
 impl SplittingState {
-  pub fn new() -> SplittingState { SplittingState }
+  pub fn new(duration: u32, from: Vec2f32, to: Vec2f32, 
+             from_rad: f32, to_rad: f32) -> SplittingState { 
+    SplittingState { 
+      tick: 0, duration, 
+      from, to, 
+      from_rad, to_rad 
+    }
+  }
+
+  /// True for the splitter man being split, which shrinks away, rather than
+  /// one of his children.
+  pub fn is_parent(&self) -> bool { self.to_rad == 0.0 }
+
   fn process(&mut self, 
-             _: &mut SplitterMan, 
-             _: &Engine) -> (Option<State>, (bool, Option<Vec<Entity>>, Vec2f32)) {
-    (None, (false, None, Vec2f32(0.0, 0.0)))
+             e: &mut SplitterMan, 
+             _: &Engine) -> Processed {
+    self.tick += 1;
+    let t = (self.tick as f32 / self.duration as f32).min(1.0);
+    e.body.rad = self.from_rad + (self.to_rad - self.from_rad)*t;
+    let new_pos = self.from.lerp(&self.to, t);
+    if self.tick < self.duration {
+      return (None, (false, None, new_pos));
+    }
+    // Done. The parent has shrunk to nothing, so remove it.
+    if self.to_rad <= 0.0 {
+      return (None, (true, None, new_pos));
+    }
+    return (Some(State::Idle(IdleState::new())), (false, None, new_pos));
   }
 }
 
+/// Moving towards the other splitter men given the same join order. Once
+/// every one of them overlaps the centre of the group, they are all removed
+/// and replaced with a single splitter man whose size is the sum of theirs.
+/// If that takes longer than the engine's max_join_ticks, they give up.
 #[derive(Copy, Clone, Debug)]
-pub struct JoiningState;
+pub struct JoiningState {
+  /// Handle of the group's leader, which spawns the joined splitter man.
+  pub leader: EHandle,
+  /// Frames spent joining so far.
+  pub tick: u32,
+}
 impl JoiningState {
-  pub fn new() -> JoiningState { JoiningState }
+  pub fn new(leader: EHandle) -> JoiningState { JoiningState { leader, tick: 0 } }
   fn process(&mut self, 
              e: &mut SplitterMan, 
-             engine: &Engine) -> (Option<State>, (bool, Option<Vec<Entity>>, Vec2f32))  {
-    (None, (false, None, Vec2f32(0.0, 0.0)))
+             engine: &Engine) -> Processed  {
+    self.tick += 1;
+    let group = match engine.join_groups.get(&self.leader) {
+      Some(g) => g,
+      None => {
+        e.stop();
+        return (Some(State::Idle(IdleState::new())), (false, None, e.body.pos));
+      },
+    };
+    let (centre, total_size) = (group.centre, group.size);
+
+    // Nothing to join with, or we can't all get together
+    if group.members.len() < 2 || self.tick > engine.max_join_ticks {
+      e.stop();
+      return (Some(State::Idle(IdleState::new())), (false, None, e.body.pos));
+    }
+
+    if group.ready {
+      // Only the leader spawns the joined splitter man, the rest are just
+      // removed.
+      if e.id == Some(self.leader) {
+        let mut joined = SplitterMan::new(centre.0, centre.1, total_size);
+        joined.selected = true;
+        // In every group any of its parts were in
+        for h in &group.members {
+          if let Some(Entity::SplitterMan(s)) = engine.entity_list.get(*h) { joined.groups |= s.groups; }
+        }
+        return (None, (true, Some(vec![Entity::SplitterMan(joined)]), e.body.pos));
+      }
+      return (None, (true, None, e.body.pos));
+    }
+
+    let dir = centre - e.body.pos;
+    if dir.len2() < e.speed*e.speed {
+      return (None, (false, None, centre));
+    }
+    // Find our way to the centre around obstacles, ignoring collisions with
+    // the rest of the group. The centre moves as the group does, so find a
+    // new path once it's strayed from the one we're following.
+    if e.target.is_none_or(|t| (t - centre).len() > e.body.rad) {
+      e.target = Some(centre);
+      e.path = None;
+    }
+    e.follow_path(engine);
+    return (None, (false, None, e.body.pos));
   }
 }
 
@@ -73,21 +186,24 @@ impl JoiningState {
 pub enum State {
   Idle(IdleState), 
   Moving(MovingState), 
-  /// Not used yet. Will be if we have animations for splitting.
   Splitting(SplittingState),
   Joining(JoiningState),
+  Holding(HoldingState),
+  Patrolling(PatrollingState),
 }
 
 impl State {
   /// Returns the state this state should change to, if necessary. Also can
   /// return whether this entity needs to be destroyed, needs to spawn any more
   /// entities, or needs to be moved to a new position after the entity loop.
-  pub fn process(&mut self, e: &mut SplitterMan, engine: &Engine) -> (Option<State>, (bool, Option<Vec<Entity>>, Vec2f32)) {
+  pub fn process(&mut self, e: &mut SplitterMan, engine: &Engine) -> Processed {
     match *self {
       State::Idle(ref mut s) => s.process(e, engine),
       State::Moving(ref mut s) => s.process(e, engine),
       State::Splitting(ref mut s) => s.process(e, engine),
       State::Joining(ref mut s) => s.process(e, engine),
+      State::Holding(ref mut s) => s.process(e, engine),
+      State::Patrolling(ref mut s) => s.process(e, engine),
     }
   }
 }
@@ -1,14 +1,15 @@
 use glium;
 use glium::backend::glutin_backend::GlutinFacade;
 use engine::Vec2f32;
+use engine::common::geometry::Rect;
 use engine::Engine;
 use shader::make_program;
 
-#[derive(Copy, Clone)]
-struct Vertex {
-  pos: [f32; 2],
+#[derive(Copy, Clone, Debug)]
+pub struct Vertex {
+  pub pos: [f32; 2],
   /// R, G, B, A
-  col: [f32; 4],
+  pub col: [f32; 4],
 }
 impl Vertex {
   #[inline(always)]
@@ -28,7 +29,11 @@ impl RendererController {
     RendererController { data: Vec::with_capacity(buf_cap), }
   }
 
+  /// Every vertex added so far, three to a triangle.
+  pub fn vertices(&self) -> &[Vertex] { &self.data }
+
   /// Create rectangle draw data and add it to the buffer.
+  #[allow(clippy::too_many_arguments)]
   pub fn rect(&mut self, x: f32, y: f32, w: f32, h: f32, r: f32, g: f32, b: f32, a: f32) {
     self.data.push(Vertex::new(x, y, r, g, b, a));
     self.data.push(Vertex::new(x+w, y, r, g, b, a));
@@ -37,8 +42,50 @@ impl RendererController {
     self.data.push(Vertex::new(x, y+h, r, g, b, a));
     self.data.push(Vertex::new(x+w, y+h, r, g, b, a));
   }
+
+  /// Create triangle draw data and add it to the buffer.
+  #[allow(clippy::too_many_arguments)]
+  pub fn tri(&mut self, p0: Vec2f32, p1: Vec2f32, p2: Vec2f32, r: f32, g: f32, b: f32, a: f32) {
+    self.data.push(Vertex::new(p0.0, p0.1, r, g, b, a));
+    self.data.push(Vertex::new(p1.0, p1.1, r, g, b, a));
+    self.data.push(Vertex::new(p2.0, p2.1, r, g, b, a));
+  }
+
+  /// Create draw data for a line `width` wide and add it to the buffer.
+  #[allow(clippy::too_many_arguments)]
+  pub fn line(&mut self, p0: Vec2f32, p1: Vec2f32, width: f32, r: f32, g: f32, b: f32, a: f32) {
+    let mut dir = p1 - p0;
+    if dir.len2() == 0.0 { return; }
+    dir.nor();
+    let side = *Vec2f32(-dir.1, dir.0).scale(width * 0.5);
+    self.tri(p0 + side, p1 + side, p1 - side, r, g, b, a);
+    self.tri(p0 + side, p1 - side, p0 - side, r, g, b, a);
+  }
+
+  /// Create draw data for a convex polygon (as a triangle fan) and add it to
+  /// the buffer.
+  pub fn poly(&mut self, verts: &[Vec2f32], r: f32, g: f32, b: f32, a: f32) {
+    for ii in 1..verts.len().saturating_sub(1) {
+      self.tri(verts[0], verts[ii], verts[ii + 1], r, g, b, a);
+    }
+  }
+}
+
+/// What happens to the camera's view of the world when the window is resized.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ResizePolicy {
+  /// Keep the same world units per pixel, so a bigger window shows more of
+  /// the world.
+  KeepScale,
+  /// Scale the world so everything which was on screen still is, with
+  /// square pixels. The side of the window which grew more shows extra world.
+  FitView,
 }
 
+/// Least and most world units per pixel the camera can zoom to.
+const MIN_ZOOM: f32 = 0.25;
+const MAX_ZOOM: f32 = 4.0;
+
 pub struct Camera {
   /// Position (center) of camera viewport in world coords
   pos: [f32; 2],
@@ -57,12 +104,82 @@ impl Camera {
                         self.pos[1] - self.size[1]/2.0, self.pos[1] + self.size[1]/2.0);
     let tx = -(r+l)/(r-l);
     let ty = -(t+b)/(t-b);
-    return [[2.0/(r-l) as f32, 0.0,           0.0, -0.0],
-    [0.0,         2.0/(t-b) as f32,  0.0,  0.0],
+    return [[2.0/(r-l), 0.0,           0.0, -0.0],
+    [0.0,         2.0/(t-b),  0.0,  0.0],
     [0.0,          0.0,          -1.0,  0.0],
     [tx,          ty,           0.0,  1.0]];
   }
 
+  /// Centre of the viewport in world coords.
+  pub fn pos(&self) -> Vec2f32 { Vec2f32(self.pos[0], self.pos[1]) }
+
+  /// Size of the viewport in world coords.
+  pub fn size(&self) -> Vec2f32 { Vec2f32(self.size[0], self.size[1]) }
+
+  /// Move the viewport so it's centred on `p`.
+  pub fn centre_on(&mut self, p: Vec2f32) { self.pos = [p.0, p.1]; }
+
+  /// Move the viewport by `d` in world coords.
+  pub fn pan(&mut self, d: Vec2f32) {
+    self.pos[0] += d.0;
+    self.pos[1] += d.1;
+  }
+
+  /// World units per pixel.
+  pub fn zoom(&self) -> f32 { self.size[0] / self.screen_size[0] as f32 }
+
+  /// Scale the viewport by `factor`, keeping the world point under the
+  /// screen point (x, y) where it is on screen. Factors above 1 zoom out.
+  /// The zoom is kept between MIN_ZOOM and MAX_ZOOM.
+  pub fn zoom_about(&mut self, x: i32, y: i32, factor: f32) {
+    let fixed = self.screen_to_world(x, y);
+    let zoom = self.zoom();
+    let factor = (zoom * factor).clamp(MIN_ZOOM, MAX_ZOOM) / zoom;
+    self.size[0] *= factor;
+    self.size[1] *= factor;
+    // Put the fixed point back under (x, y)
+    let moved = self.screen_to_world(x, y);
+    self.pan(fixed - moved);
+  }
+
+  /// Move the viewport so it doesn't show anything outside `bounds`. If the
+  /// viewport is bigger than the bounds, it's centred on them instead.
+  pub fn clamp_to(&mut self, bounds: &Rect) {
+    let (min, max) = (bounds.pos, bounds.max());
+    let clamp = |pos: f32, size: f32, min: f32, max: f32| {
+      if size >= max - min { (min + max) * 0.5 }
+      else { pos.max(min + size*0.5).min(max - size*0.5) }
+    };
+    self.pos[0] = clamp(self.pos[0], self.size[0], min.0, max.0);
+    self.pos[1] = clamp(self.pos[1], self.size[1], min.1, max.1);
+  }
+
+  /// Size of the screen in pixels.
+  pub fn screen_size(&self) -> (i32, i32) { (self.screen_size[0], self.screen_size[1]) }
+
+  /// Change the size of the screen, keeping the viewport centred where it
+  /// was.
+  pub fn resize(&mut self, screen_w: i32, screen_h: i32, policy: ResizePolicy) {
+    if screen_w <= 0 || screen_h <= 0 { return; }
+    let zoom = match policy {
+      ResizePolicy::KeepScale => self.zoom(),
+      ResizePolicy::FitView => {
+        (self.size[0] / screen_w as f32).max(self.size[1] / screen_h as f32).clamp(MIN_ZOOM, MAX_ZOOM)
+      },
+    };
+    self.screen_size = [screen_w, screen_h];
+    self.size = [screen_w as f32 * zoom, screen_h as f32 * zoom];
+  }
+
+  /// Projection for drawing in screen coords, i.e. the HUD.
+  pub fn gen_hud_proj_mat(&self) -> [[f32; 4]; 4] {
+    let (w, h) = (self.screen_size[0] as f32, self.screen_size[1] as f32);
+    return [[2.0/w, 0.0,     0.0, -0.0],
+    [0.0,        -2.0/h,  0.0,  0.0],
+    [0.0,         0.0,   -1.0,  0.0],
+    [-1.0,        1.0,    0.0,  1.0]];
+  }
+
   /// Convert screen coords to world coords.
   pub fn screen_to_world(&self, x: i32, y: i32) -> Vec2f32 {
     // Get distance from the centre
@@ -74,11 +191,18 @@ impl Camera {
     // Convert distance to pos
     return Vec2f32(self.pos[0] + x_dis_w, self.pos[1] + y_dis_w);
   }
+
+  /// Convert world coords to screen coords. The opposite of screen_to_world,
+  /// but not rounded to whole pixels.
+  pub fn world_to_screen(&self, p: Vec2f32) -> Vec2f32 {
+    let x_dis = (p.0 - self.pos[0]) * (self.screen_size[0] as f32/self.size[0]);
+    let y_dis = (p.1 - self.pos[1]) * (self.screen_size[1] as f32/self.size[1]);
+    return Vec2f32((self.screen_size[0]/2) as f32 + x_dis, (self.screen_size[1]/2) as f32 + y_dis);
+  }
 }
 
 pub struct Renderer {
   program: glium::Program,
-  pub camera: Camera,
   vbo: glium::VertexBuffer<Vertex>,
   proj_mat: [[f32; 4]; 4],
   hud_vbo: glium::VertexBuffer<Vertex>,
@@ -91,9 +215,8 @@ impl Renderer {
 
     Renderer {
       program: make_program(display),
-      camera: Camera::new(w as f32, h as f32, w as i32, h as i32),
       vbo: glium::VertexBuffer::empty_dynamic(display, 65536).unwrap(),
-      hud_vbo: glium::VertexBuffer::empty_dynamic(display, 1024).unwrap(),
+      hud_vbo: glium::VertexBuffer::empty_dynamic(display, 16384).unwrap(),
       proj_mat: [[2.0/w as f32, 0.0,           0.0, -0.0],
       [0.0,         -2.0/h as f32,  0.0,  0.0],
       [0.0,          0.0,          -1.0,  0.0],
@@ -105,9 +228,10 @@ impl Renderer {
     }
   }
 
-  /// Update projection mat to reflect camera
-  pub fn update_proj_mat(&mut self) {
-    self.proj_mat = self.camera.gen_proj_mat();
+  /// Update projection mats to reflect camera, and the size of the screen
+  pub fn update_proj_mat(&mut self, camera: &Camera) {
+    self.proj_mat = camera.gen_proj_mat();
+    self.hud_proj_mat = camera.gen_hud_proj_mat();
   }
 
   pub fn render(&self, target: &mut glium::Frame, engine: &Engine) {
@@ -123,11 +247,23 @@ impl Renderer {
     // Render entities and tiles
     let mut controller = RendererController::new(self.vbo.len());
 
+    if let Some(ref level) = engine.level {
+      // Bounds outline
+      let (min, max, t) = (level.bounds.pos, level.bounds.max(), 2.0);
+      controller.rect(min.0 - t, min.1 - t, max.0 - min.0 + t*2.0, t, 0.5, 0.5, 0.5, 1.0);
+      controller.rect(min.0 - t, max.1, max.0 - min.0 + t*2.0, t, 0.5, 0.5, 0.5, 1.0);
+      controller.rect(min.0 - t, min.1, t, max.1 - min.1, 0.5, 0.5, 0.5, 1.0);
+      controller.rect(max.0, min.1, t, max.1 - min.1, 0.5, 0.5, 0.5, 1.0);
+      for g in &level.goals {
+        controller.rect(g.pos.0, g.pos.1, g.size.0, g.size.1, 0.0, 1.0, 0.0, 0.3);
+      }
+    }
+
     for e in &engine.entity_list {
       e.get().render(&mut controller);
     }
 
-    if controller.data.len() == 0 { return }
+    if controller.data.is_empty() { return }
     controller.data.resize(self.vbo.len(), Vertex::new(0.0, 0.0, 0.0, 0.0, 0.0, 0.0));
     self.vbo.write(&controller.data);
 
@@ -136,7 +272,7 @@ impl Renderer {
     };
 
     target.draw(&self.vbo,
-                &indices, 
+                indices, 
                 &self.program, 
                 &uniforms,
                 &params).unwrap();
@@ -145,16 +281,24 @@ impl Renderer {
     controller = RendererController::new(self.hud_vbo.len());
 
     // Selection box
-    if engine.input_handler.curr_box.is_some() {
-      let b = engine.input_handler.curr_box.as_ref().unwrap();
+    if let Some(ref b) = engine.input_handler.curr_box {
       controller.rect(b[0].0, b[0].1, b[1].0 - b[0].0, b[1].1 - b[0].1, 0.0, 1.0, 1.0, 0.4);
     }
 
+    // Lasso, closed back to where it started
+    if let Some(ref l) = engine.input_handler.curr_lasso {
+      for ii in 0..l.len() {
+        controller.line(l[ii], l[(ii + 1) % l.len()], 2.0, 0.0, 1.0, 1.0, 0.8);
+      }
+    }
+
+    if engine.level.is_some() { engine.minimap.render(engine, &mut controller); }
+
     // Mouse
     let m = engine.input_handler.mouse_pos;
     controller.rect(m.0 as f32, m.1 as f32, 4.0, 4.0, 1.0, 1.0, 1.0, 1.0);
 
-    if controller.data.len() == 0 { return }
+    if controller.data.is_empty() { return }
     controller.data.resize(self.hud_vbo.len(), Vertex::new(0.0, 0.0, 0.0, 0.0, 0.0, 0.0));
     self.hud_vbo.write(&controller.data);
 
@@ -164,7 +308,7 @@ impl Renderer {
     };
 
     target.draw(&self.hud_vbo,
-                &indices, 
+                indices, 
                 &self.program, 
                 &uniforms,
                 &params).unwrap();
@@ -172,3 +316,112 @@ impl Renderer {
 
   }
 }
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn close(a: Vec2f32, b: Vec2f32) -> bool { (a - b).len() < 0.01 }
+
+  /// Where the projection matrix puts a world point, in screen coords.
+  fn project(c: &Camera, p: Vec2f32) -> Vec2f32 {
+    let m = c.gen_proj_mat();
+    let ndc = Vec2f32(m[0][0]*p.0 + m[3][0], m[1][1]*p.1 + m[3][1]);
+    let (w, h) = c.screen_size();
+    return Vec2f32((ndc.0 + 1.0) * 0.5 * w as f32, (1.0 - ndc.1) * 0.5 * h as f32);
+  }
+
+  fn cameras() -> Vec<Camera> {
+    let mut cams = Vec::new();
+    for &zoom in &[1.0, 0.5, 2.5] {
+      let mut c = Camera::new(800.0, 600.0, 800, 600);
+      c.zoom_about(400, 300, zoom);
+      c.pan(Vec2f32(123.0, -45.0));
+      cams.push(c);
+    }
+    return cams;
+  }
+
+  #[test]
+  fn screen_world_round_trip() {
+    for c in cameras() {
+      for &(x, y) in &[(0, 0), (400, 300), (799, 599), (13, 577)] {
+        let s = c.world_to_screen(c.screen_to_world(x, y));
+        assert!(close(s, Vec2f32(x as f32, y as f32)), "{:?} != {:?}", s, (x, y));
+      }
+    }
+  }
+
+  #[test]
+  fn proj_mat_matches_world_to_screen() {
+    for c in cameras() {
+      for &p in &[Vec2f32(0.0, 0.0), Vec2f32(200.0, -70.0), Vec2f32(-512.0, 333.0)] {
+        assert!(close(project(&c, p), c.world_to_screen(p)));
+      }
+    }
+  }
+
+  #[test]
+  fn zoom_keeps_point_under_cursor() {
+    let mut c = Camera::new(800.0, 600.0, 800, 600);
+    let before = c.screen_to_world(100, 500);
+    c.zoom_about(100, 500, 0.5);
+    assert!(close(c.screen_to_world(100, 500), before));
+    assert!((c.zoom() - 0.5).abs() < 1e-6);
+    // Clamped
+    c.zoom_about(100, 500, 0.01);
+    assert!((c.zoom() - MIN_ZOOM).abs() < 1e-6);
+    assert!(close(c.screen_to_world(100, 500), before));
+  }
+
+  #[test]
+  fn hud_proj_mat_maps_screen_corners() {
+    let mut c = Camera::new(800.0, 600.0, 800, 600);
+    c.resize(1024, 768, ResizePolicy::KeepScale);
+    let m = c.gen_hud_proj_mat();
+    let ndc = |x: f32, y: f32| Vec2f32(m[0][0]*x + m[3][0], m[1][1]*y + m[3][1]);
+    assert!(close(ndc(0.0, 0.0), Vec2f32(-1.0, 1.0)));
+    assert!(close(ndc(1024.0, 768.0), Vec2f32(1.0, -1.0)));
+  }
+
+  #[test]
+  fn resize_keep_scale() {
+    let mut c = Camera::new(800.0, 600.0, 800, 600);
+    c.zoom_about(400, 300, 2.0);
+    c.resize(1000, 500, ResizePolicy::KeepScale);
+    assert!((c.zoom() - 2.0).abs() < 1e-6);
+    assert!(close(c.size(), Vec2f32(2000.0, 1000.0)));
+    assert!(close(c.pos(), Vec2f32(0.0, 0.0)));
+  }
+
+  #[test]
+  fn resize_fit_view() {
+    let mut c = Camera::new(800.0, 600.0, 800, 600);
+    c.resize(400, 600, ResizePolicy::FitView);
+    // Still shows the whole 800 wide view, and more height
+    assert!((c.zoom() - 2.0).abs() < 1e-6);
+    assert!(close(c.size(), Vec2f32(800.0, 1200.0)));
+    // Hit testing still lines up with what's drawn
+    for c in cameras() {
+      let mut c = c;
+      c.resize(640, 480, ResizePolicy::FitView);
+      let p = c.screen_to_world(20, 460);
+      assert!(close(project(&c, p), Vec2f32(20.0, 460.0)));
+    }
+  }
+
+  #[test]
+  fn clamp_to_bounds() {
+    let bounds = Rect::new(0.0, 0.0, 2000.0, 1000.0);
+    let mut c = Camera::new(800.0, 600.0, 800, 600);
+    c.clamp_to(&bounds);
+    assert!(close(c.pos(), Vec2f32(400.0, 300.0)));
+    c.centre_on(Vec2f32(5000.0, 5000.0));
+    c.clamp_to(&bounds);
+    assert!(close(c.pos(), Vec2f32(1600.0, 700.0)));
+    // Zoomed out past the bounds, so centre on them
+    c.zoom_about(400, 300, 4.0);
+    c.clamp_to(&bounds);
+    assert!(close(c.pos(), Vec2f32(1000.0, 500.0)));
+  }
+}
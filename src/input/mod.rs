@@ -1,7 +1,49 @@
 use std::collections::BTreeMap;
-use glium::glutin::{Event, ElementState, VirtualKeyCode, MouseButton};
+use std::cell::RefCell;
+use std::rc::Rc;
+use std::mem;
+use glium::glutin::{Event, ElementState, VirtualKeyCode, MouseButton, MouseScrollDelta};
 use glium::backend::glutin_backend::GlutinFacade;
 use engine::Vec2f32;
+use engine::command::{Command, Order};
+use entity::EHandle;
+
+/// Recording input to files and playing it back.
+pub mod replay;
+
+pub use self::replay::{InputRecord, ReplayRecorder, Replay, ReplaySettings};
+
+/// Somewhere the InputHandler can get window events from. Normally this is
+/// the window itself, but a headless engine can be fed events by hand.
+pub trait InputSource {
+  /// Return all events that have happened since the last poll.
+  fn poll(&mut self) -> Vec<Event>;
+}
+
+impl InputSource for GlutinFacade {
+  fn poll(&mut self) -> Vec<Event> { self.poll_events().collect() }
+}
+
+/// An input source which hands out events pushed onto it. Clones share the
+/// same queue, so keep a clone around to push events after giving one to the
+/// engine.
+#[derive(Clone, Default)]
+pub struct QueuedInput {
+  events: Rc<RefCell<Vec<Event>>>,
+}
+
+impl QueuedInput {
+  pub fn new() -> QueuedInput { QueuedInput { events: Rc::new(RefCell::new(Vec::new())) } }
+
+  /// Queue an event to be returned on the next poll.
+  pub fn push(&self, e: Event) { self.events.borrow_mut().push(e); }
+}
+
+impl InputSource for QueuedInput {
+  fn poll(&mut self) -> Vec<Event> {
+    self.events.borrow_mut().drain(..).collect()
+  }
+}
 
 #[derive(Clone, PartialEq, Hash)]
 pub enum InputType {
@@ -12,25 +54,141 @@ pub enum InputType {
 #[derive(Clone, Hash)]
 pub struct Input {
   pub input: InputType,
+  /// Another key or button which does the same thing
+  pub alt: Option<InputType>,
   pub down: bool,
   pub just_down: bool,
 }
 
 impl Input {
   pub fn new_key_input(k: VirtualKeyCode) -> Input {
-    Input { input: InputType::Key(k), down: false, just_down: false }
+    Input { input: InputType::Key(k), alt: None, down: false, just_down: false }
   }
   pub fn new_mouse_input(m: MouseButton) -> Input {
-    Input { input: InputType::Mouse(m), down: false, just_down: false }
+    Input { input: InputType::Mouse(m), alt: None, down: false, just_down: false }
+  }
+
+  /// Also bind this input to another key.
+  pub fn or_key(mut self, k: VirtualKeyCode) -> Input {
+    self.alt = Some(InputType::Key(k));
+    return self;
+  }
+
+  /// Whether this input is bound to a key or button.
+  pub fn is_bound_to(&self, t: &InputType) -> bool {
+    self.input == *t || self.alt.as_ref() == Some(t)
   }
 
   pub fn pressed(&mut self) { self.down = true; self.just_down = true; }
   pub fn released(&mut self) { self.down = false; self.just_down = false; }
 }
 
-#[derive(Ord, Eq, PartialOrd, PartialEq, Hash)]
+#[derive(Ord, Eq, PartialOrd, PartialEq, Hash, Clone, Copy, Debug)]
 pub enum Control {
-  Split, Select, Move, Join
+  Split, Select, Move, Join, 
+  /// Cycle the formation groups are moved in
+  Formation,
+  /// Held while moving to queue the move after the current one
+  Queue,
+  /// Stop and forget every order
+  Stop,
+  /// Stop and stay put, even when pushed
+  Hold,
+  /// Held while moving to add patrol points. Patrolling starts when it's
+  /// released.
+  Patrol,
+  /// Select control group n, or centre the camera on it if pressed twice
+  Group(u8),
+  /// Held while pressing a group key to put the selection in that group
+  SetGroup,
+  /// Held while selecting to add to the selection
+  AddSelect,
+  /// Held while selecting to toggle whether units are selected
+  ToggleSelect,
+  /// Select every unit
+  SelectAll,
+  /// Held when starting a selection to draw a lasso rather than a box
+  Lasso,
+  /// Move the camera
+  PanUp, PanDown, PanLeft, PanRight,
+}
+
+/// Most points a lasso can have. Any more mouse movement is ignored.
+const MAX_LASSO_POINTS: usize = 128;
+
+/// Pixels the mouse has to move to add another point to a lasso.
+const LASSO_SPACING: f32 = 6.0;
+
+/// Pixels of smooth scrolling which count as scrolling the mouse wheel one
+/// line.
+const WHEEL_PIXELS_PER_LINE: f32 = 20.0;
+
+/// Number of control groups, one per number key. At most 16, as each
+/// splitter man keeps the groups he's in as bits of a u16.
+pub const NUM_CONTROL_GROUPS: u8 = 10;
+
+/// Names of the group controls, indexed by group.
+const GROUP_NAMES: [&str; NUM_CONTROL_GROUPS as usize] = [
+  "Group0", "Group1", "Group2", "Group3", "Group4",
+  "Group5", "Group6", "Group7", "Group8", "Group9",
+];
+
+/// Keys for each control group.
+const GROUP_KEYS: [VirtualKeyCode; NUM_CONTROL_GROUPS as usize] = [
+  VirtualKeyCode::Key0, VirtualKeyCode::Key1, VirtualKeyCode::Key2, VirtualKeyCode::Key3,
+  VirtualKeyCode::Key4, VirtualKeyCode::Key5, VirtualKeyCode::Key6, VirtualKeyCode::Key7,
+  VirtualKeyCode::Key8, VirtualKeyCode::Key9,
+];
+
+impl Control {
+  /// Name of the control, as written to replay files.
+  pub fn name(&self) -> &'static str {
+    match *self {
+      Control::Split => "Split",
+      Control::Select => "Select",
+      Control::Move => "Move",
+      Control::Join => "Join",
+      Control::Formation => "Formation",
+      Control::Queue => "Queue",
+      Control::Stop => "Stop",
+      Control::Hold => "Hold",
+      Control::Patrol => "Patrol",
+      Control::Group(n) => GROUP_NAMES[n as usize],
+      Control::SetGroup => "SetGroup",
+      Control::AddSelect => "AddSelect",
+      Control::ToggleSelect => "ToggleSelect",
+      Control::SelectAll => "SelectAll",
+      Control::Lasso => "Lasso",
+      Control::PanUp => "PanUp",
+      Control::PanDown => "PanDown",
+      Control::PanLeft => "PanLeft",
+      Control::PanRight => "PanRight",
+    }
+  }
+
+  pub fn from_name(name: &str) -> Option<Control> {
+    match name {
+      "Split" => Some(Control::Split),
+      "Select" => Some(Control::Select),
+      "Move" => Some(Control::Move),
+      "Join" => Some(Control::Join),
+      "Formation" => Some(Control::Formation),
+      "Queue" => Some(Control::Queue),
+      "Stop" => Some(Control::Stop),
+      "Hold" => Some(Control::Hold),
+      "Patrol" => Some(Control::Patrol),
+      "SetGroup" => Some(Control::SetGroup),
+      "AddSelect" => Some(Control::AddSelect),
+      "ToggleSelect" => Some(Control::ToggleSelect),
+      "SelectAll" => Some(Control::SelectAll),
+      "Lasso" => Some(Control::Lasso),
+      "PanUp" => Some(Control::PanUp),
+      "PanDown" => Some(Control::PanDown),
+      "PanLeft" => Some(Control::PanLeft),
+      "PanRight" => Some(Control::PanRight),
+      _ => GROUP_NAMES.iter().position(|g| *g == name).map(|n| Control::Group(n as u8)),
+    }
+  }
 }
 
 pub struct InputHandler {
@@ -38,8 +196,29 @@ pub struct InputHandler {
   pub curr_box: Option<[Vec2f32; 2]>,
   /// Will be set for 1 frame after curr_box is stopped dragging.
   pub selection: Option<[Vec2f32; 2]>,
+  /// Outline of the lasso currently being dragged, in screen coords
+  pub curr_lasso: Option<Vec<Vec2f32>>,
+  /// Will be set for 1 frame after curr_lasso is stopped dragging.
+  pub lasso: Option<Vec<Vec2f32>>,
   pub mouse_pos: (i32, i32),
+  /// Whether mouse_pos has ever changed. Until then it's (0, 0) rather than
+  /// wherever the cursor really is.
+  pub mouse_moved: bool,
+  /// Lines the mouse wheel scrolled this frame. Positive is away from the
+  /// player.
+  pub wheel: f32,
+  /// New size of the window in pixels, if it was resized this frame.
+  pub resized: Option<(i32, i32)>,
   pub inputs: BTreeMap<Control, Input>,
+  /// World points clicked while the patrol control is held.
+  pub patrol_points: Vec<Vec2f32>,
+  /// Where the last move order went while the move control is held, so
+  /// holding it still only sends a new order when the cursor moves.
+  last_move: Option<Vec2f32>,
+}
+
+impl Default for InputHandler {
+  fn default() -> InputHandler { InputHandler::new() }
 }
 
 impl InputHandler {
@@ -47,14 +226,38 @@ impl InputHandler {
     let mut i = InputHandler { 
       curr_box: None,
       selection: None,
+      curr_lasso: None,
+      lasso: None,
       mouse_pos: (0, 0),
+      mouse_moved: false,
+      wheel: 0.0,
+      resized: None,
       inputs: BTreeMap::new(),
+      patrol_points: Vec::new(),
+      last_move: None,
     };
 
     i.inputs.insert(Control::Select, Input::new_mouse_input(MouseButton::Left));
     i.inputs.insert(Control::Move, Input::new_mouse_input(MouseButton::Right));
     i.inputs.insert(Control::Split, Input::new_key_input(VirtualKeyCode::Space));
     i.inputs.insert(Control::Join, Input::new_key_input(VirtualKeyCode::R));
+    i.inputs.insert(Control::Formation, Input::new_key_input(VirtualKeyCode::F));
+    i.inputs.insert(Control::Queue, Input::new_key_input(VirtualKeyCode::LShift));
+    i.inputs.insert(Control::Stop, Input::new_key_input(VirtualKeyCode::X));
+    i.inputs.insert(Control::Hold, Input::new_key_input(VirtualKeyCode::H));
+    i.inputs.insert(Control::Patrol, Input::new_key_input(VirtualKeyCode::P));
+    i.inputs.insert(Control::SetGroup, Input::new_key_input(VirtualKeyCode::LControl));
+    i.inputs.insert(Control::AddSelect, Input::new_key_input(VirtualKeyCode::LShift));
+    i.inputs.insert(Control::ToggleSelect, Input::new_key_input(VirtualKeyCode::LControl));
+    i.inputs.insert(Control::SelectAll, Input::new_key_input(VirtualKeyCode::Tab));
+    i.inputs.insert(Control::Lasso, Input::new_key_input(VirtualKeyCode::LAlt));
+    i.inputs.insert(Control::PanUp, Input::new_key_input(VirtualKeyCode::W).or_key(VirtualKeyCode::Up));
+    i.inputs.insert(Control::PanDown, Input::new_key_input(VirtualKeyCode::S).or_key(VirtualKeyCode::Down));
+    i.inputs.insert(Control::PanLeft, Input::new_key_input(VirtualKeyCode::A).or_key(VirtualKeyCode::Left));
+    i.inputs.insert(Control::PanRight, Input::new_key_input(VirtualKeyCode::D).or_key(VirtualKeyCode::Right));
+    for n in 0..NUM_CONTROL_GROUPS {
+      i.inputs.insert(Control::Group(n), Input::new_key_input(GROUP_KEYS[n as usize]));
+    }
 
     return i;
   }
@@ -62,20 +265,20 @@ impl InputHandler {
   /// Returns true if user requests close
   fn record_key_input(&mut self, state: ElementState, 
                       keycode: VirtualKeyCode) -> bool {
-    for (_, mut input) in &mut self.inputs.iter_mut() {
-      if input.input != InputType::Key(keycode) { continue; }
+    for (_, input) in &mut self.inputs.iter_mut() {
+      if !input.is_bound_to(&InputType::Key(keycode)) { continue; }
       match state {
         ElementState::Pressed => input.pressed(),
         ElementState::Released => input.released(),
       }
     }
-    if keycode == VirtualKeyCode::Escape { true } else { false }
+    keycode == VirtualKeyCode::Escape
   }
 
   fn record_mouse_input(&mut self, state: ElementState, 
                       button: MouseButton) {
-    for (_, mut input) in &mut self.inputs.iter_mut() {
-      if input.input != InputType::Mouse(button) { continue; }
+    for (_, input) in &mut self.inputs.iter_mut() {
+      if !input.is_bound_to(&InputType::Mouse(button)) { continue; }
       match state {
         ElementState::Pressed => input.pressed(),
         ElementState::Released => input.released(),
@@ -83,40 +286,109 @@ impl InputHandler {
     }
   }
 
+  /// Works out whether the mouse has moved from the position alone, so
+  /// replays, which only record the position, agree with the live game.
+  fn move_mouse(&mut self, pos: (i32, i32)) {
+    if pos != self.mouse_pos { self.mouse_moved = true; }
+    self.mouse_pos = pos;
+  }
+
   fn reset_just_pressed(&mut self) {
-    for (_, mut input) in self.inputs.iter_mut() {
+    for (_, input) in self.inputs.iter_mut() {
       input.just_down = false;
     }
   }
 
   // Check input. Return true if used requested to quit. 
-  pub fn check_input(&mut self, display: &GlutinFacade) -> bool {
+  pub fn check_input(&mut self, source: &mut dyn InputSource) -> bool {
     self.reset_just_pressed();
     self.selection = None;
-    for e in display.poll_events() {
+    self.lasso = None;
+    self.wheel = 0.0;
+    self.resized = None;
+    let mut quit = false;
+    for e in source.poll() {
       match e {
         Event::Closed => return true,
-        Event::KeyboardInput(state, _, keycode) => {
-          if keycode.is_none() { return false; }
-          let keycode = keycode.unwrap();
-          return self.record_key_input(state, keycode);
-        },
+        Event::KeyboardInput(state, _, Some(keycode)) => quit |= self.record_key_input(state, keycode),
         Event::MouseInput(state, button) => self.record_mouse_input(state, button),
-        Event::MouseMoved(x, y) => self.mouse_pos = (x, y),
+        Event::MouseMoved(x, y) => self.move_mouse((x, y)),
+        Event::Resized(w, h) => self.resized = Some((w as i32, h as i32)),
+        Event::MouseWheel(MouseScrollDelta::LineDelta(_, y), _) => self.wheel += y,
+        Event::MouseWheel(MouseScrollDelta::PixelDelta(_, y), _) => self.wheel += y / WHEEL_PIXELS_PER_LINE,
         _ => (),
       }
     }
 
     self.process_input();
-    return false;
+    return quit;
+  }
+
+  /// Set this frame's input from a recording rather than from window events.
+  pub fn apply_record(&mut self, record: &InputRecord) {
+    self.selection = None;
+    self.lasso = None;
+    self.move_mouse(record.mouse_pos);
+    self.wheel = record.wheel;
+    self.resized = record.resized;
+    for (c, input) in self.inputs.iter_mut() {
+      let (down, just_down) = record.get(*c);
+      input.down = down;
+      input.just_down = just_down;
+    }
+    self.process_input();
+  }
+
+  /// Turn this frame's controls into orders for the selected splitter men.
+  /// `dest` is the world point under the cursor.
+  pub fn commands(&mut self, selected: &[EHandle], dest: Vec2f32) -> Vec<Command> {
+    let mut commands = Vec::new();
+    if selected.is_empty() {
+      self.patrol_points.clear();
+      return commands;
+    }
+    let inputs = &self.inputs;
+    let down = |c| inputs.get(&c).unwrap().down;
+    let just_down = |c| inputs.get(&c).unwrap().just_down;
+    if just_down(Control::Split) {
+      commands.push(Command::new(selected.to_vec(), Order::Split));
+    }
+    if down(Control::Patrol) {
+      if just_down(Control::Move) && !down(Control::Select) { self.patrol_points.push(dest); }
+    }
+    else if down(Control::Move) && !down(Control::Select) {
+      // Only queue once per click. Otherwise dragging with the button held
+      // steers the move, but holding it still doesn't repeat it.
+      let queue = down(Control::Queue);
+      if just_down(Control::Move) || (!queue && self.last_move != Some(dest)) {
+        commands.push(Command::new(selected.to_vec(), Order::Move { dest, queue }));
+        self.last_move = Some(dest);
+      }
+    }
+    if !down(Control::Move) { self.last_move = None; }
+    if !down(Control::Patrol) && !self.patrol_points.is_empty() {
+      let points = mem::take(&mut self.patrol_points);
+      commands.push(Command::new(selected.to_vec(), Order::Patrol(points)));
+    }
+    if just_down(Control::Join) {
+      commands.push(Command::new(selected.to_vec(), Order::Join));
+    }
+    if just_down(Control::Stop) {
+      commands.push(Command::new(selected.to_vec(), Order::Stop));
+    }
+    if just_down(Control::Hold) {
+      commands.push(Command::new(selected.to_vec(), Order::Hold));
+    }
+    return commands;
   }
 
   /// Do actual processing of controls data, rather than just recording values.
   fn process_input(&mut self) {
+    let mouse = Vec2f32(self.mouse_pos.0 as f32, self.mouse_pos.1 as f32);
     let c_select = self.inputs.get(&Control::Select).unwrap();
     if c_select.just_down {
-      self.curr_box = Some([Vec2f32(self.mouse_pos.0 as f32, self.mouse_pos.1 as f32),
-                           Vec2f32(self.mouse_pos.0 as f32, self.mouse_pos.1 as f32)]);
+      if self.inputs.get(&Control::Lasso).unwrap().down { self.curr_lasso = Some(vec![mouse]); }
+      else { self.curr_box = Some([mouse, mouse]); }
     }
     else if self.curr_box.is_some() {
       if !c_select.down { 
@@ -124,11 +396,86 @@ impl InputHandler {
         self.curr_box = None; 
       }
       else {
-        let mut b = self.curr_box.as_mut().unwrap();
+        let b = self.curr_box.as_mut().unwrap();
         b[1].0 = self.mouse_pos.0 as f32;
         b[1].1 = self.mouse_pos.1 as f32;
       }
     }
+    else if self.curr_lasso.is_some() {
+      if !c_select.down {
+        self.lasso = self.curr_lasso.take();
+      }
+      else {
+        let l = self.curr_lasso.as_mut().unwrap();
+        let last = l[l.len() - 1];
+        if l.len() < MAX_LASSO_POINTS && (mouse - last).len2() >= LASSO_SPACING*LASSO_SPACING {
+          l.push(mouse);
+        }
+      }
+    }
   }
+
 }
 
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn moves(commands: &[Command]) -> usize {
+    commands.iter().filter(|c| matches!(c.order, Order::Move { .. })).count()
+  }
+
+  #[test]
+  fn holding_move_orders_once() {
+    let mut i = InputHandler::new();
+    let sel = [EHandle(0, 0)];
+    i.inputs.get_mut(&Control::Move).unwrap().pressed();
+    assert_eq!(moves(&i.commands(&sel, Vec2f32(10.0, 10.0))), 1);
+    i.reset_just_pressed();
+    // Held still
+    assert_eq!(moves(&i.commands(&sel, Vec2f32(10.0, 10.0))), 0);
+    assert_eq!(moves(&i.commands(&sel, Vec2f32(10.0, 10.0))), 0);
+    // Dragged somewhere new
+    assert_eq!(moves(&i.commands(&sel, Vec2f32(20.0, 10.0))), 1);
+    assert_eq!(moves(&i.commands(&sel, Vec2f32(20.0, 10.0))), 0);
+    // Clicking the same spot again still orders a move
+    i.inputs.get_mut(&Control::Move).unwrap().released();
+    assert_eq!(moves(&i.commands(&sel, Vec2f32(20.0, 10.0))), 0);
+    i.inputs.get_mut(&Control::Move).unwrap().pressed();
+    assert_eq!(moves(&i.commands(&sel, Vec2f32(20.0, 10.0))), 1);
+  }
+
+  #[test]
+  fn every_event_in_a_frame_counts() {
+    let mut i = InputHandler::new();
+    let mut input = QueuedInput::new();
+    // A chord, a tap and a mouse move all in one frame
+    input.push(Event::KeyboardInput(ElementState::Pressed, 0, Some(VirtualKeyCode::LControl)));
+    input.push(Event::KeyboardInput(ElementState::Pressed, 0, Some(VirtualKeyCode::Key1)));
+    input.push(Event::KeyboardInput(ElementState::Pressed, 0, Some(VirtualKeyCode::Space)));
+    input.push(Event::KeyboardInput(ElementState::Released, 0, Some(VirtualKeyCode::Space)));
+    input.push(Event::MouseMoved(30, 40));
+    assert!(!i.check_input(&mut input));
+    assert!(i.inputs.get(&Control::SetGroup).unwrap().down);
+    assert!(i.inputs.get(&Control::Group(1)).unwrap().just_down);
+    assert!(!i.inputs.get(&Control::Split).unwrap().down);
+    assert_eq!(i.mouse_pos, (30, 40));
+    // Quitting still waits for the rest of the frame
+    input.push(Event::KeyboardInput(ElementState::Pressed, 0, Some(VirtualKeyCode::Escape)));
+    input.push(Event::KeyboardInput(ElementState::Released, 0, Some(VirtualKeyCode::LControl)));
+    assert!(i.check_input(&mut input));
+    assert!(!i.inputs.get(&Control::SetGroup).unwrap().down);
+  }
+
+  #[test]
+  fn queued_moves_only_on_click() {
+    let mut i = InputHandler::new();
+    let sel = [EHandle(0, 0)];
+    i.inputs.get_mut(&Control::Queue).unwrap().pressed();
+    i.inputs.get_mut(&Control::Move).unwrap().pressed();
+    assert_eq!(moves(&i.commands(&sel, Vec2f32(10.0, 10.0))), 1);
+    i.reset_just_pressed();
+    assert_eq!(moves(&i.commands(&sel, Vec2f32(30.0, 10.0))), 0);
+  }
+}
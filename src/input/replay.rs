@@ -0,0 +1,317 @@
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::path::Path;
+use input::{Control, InputHandler};
+use game_renderer::ResizePolicy;
+
+/// Version written to the header of replay files. Bump this if the format
+/// changes.
+pub const REPLAY_VERSION: u32 = 5;
+
+/// First word of every replay file.
+const REPLAY_MAGIC: &str = "splitterman-replay";
+
+/// The state of every control and the mouse for one frame.
+///
+/// # File format
+/// Replay files are text. The first line is
+/// `splitterman-replay <version> <screen width> <screen height> <edge scroll 0|1>
+/// <pan speed> <resize policy> <level lines>`, the screen size and camera
+/// settings being needed to turn mouse positions back into the same world
+/// positions. `<resize policy>` is `KeepScale` or `FitView`. The next `<level lines>` lines are the level file the
+/// replay was recorded on, so the replay can be played back on its own. There
+/// are none if no level was loaded. Every line after that is one frame, in
+/// order:
+///
+/// `<mouse x> <mouse y> <wheel> [resize <width> <height>] [<control>[+] ...]`
+///
+/// Each control listed is held down on that frame, and a `+` after its name
+/// means it was pressed on that frame. Controls not listed are up. Control
+/// names are the names of the `Control` variants, i.e. `Move`. `<wheel>` is
+/// the number of lines the mouse wheel scrolled that frame, and `resize` is
+/// only there on frames the window was resized on.
+#[derive(Clone, Debug, PartialEq)]
+pub struct InputRecord {
+  pub mouse_pos: (i32, i32),
+  /// Lines the mouse wheel scrolled this frame
+  pub wheel: f32,
+  /// New size of the window, if it was resized this frame
+  pub resized: Option<(i32, i32)>,
+  /// Controls held down this frame, and whether they were just pressed
+  pub controls: Vec<(Control, bool)>,
+}
+
+impl InputRecord {
+  /// Record the current frame's input from the input handler.
+  pub fn from_handler(handler: &InputHandler) -> InputRecord {
+    let mut controls = Vec::new();
+    for (c, input) in &handler.inputs {
+      if input.down { controls.push((*c, input.just_down)); }
+    }
+    InputRecord { mouse_pos: handler.mouse_pos, wheel: handler.wheel, resized: handler.resized, controls }
+  }
+
+  /// Get (down, just_down) for a control.
+  pub fn get(&self, c: Control) -> (bool, bool) {
+    for &(rc, just_down) in &self.controls {
+      if rc == c { return (true, just_down); }
+    }
+    return (false, false);
+  }
+
+  fn write_line(&self, out: &mut dyn Write) -> io::Result<()> {
+    write!(out, "{} {} {:?}", self.mouse_pos.0, self.mouse_pos.1, self.wheel)?;
+    if let Some((w, h)) = self.resized { write!(out, " resize {} {}", w, h)?; }
+    for &(c, just_down) in &self.controls {
+      write!(out, " {}{}", c.name(), if just_down { "+" } else { "" })?;
+    }
+    writeln!(out)
+  }
+
+  fn parse_line(line: &str) -> Option<InputRecord> {
+    let mut words = line.split_whitespace();
+    let x = words.next()?.parse().ok()?;
+    let y = words.next()?.parse().ok()?;
+    let wheel = words.next()?.parse().ok()?;
+    let mut resized = None;
+    if words.clone().next() == Some("resize") {
+      words.next();
+      resized = Some((words.next()?.parse().ok()?, words.next()?.parse().ok()?));
+    }
+    let mut controls = Vec::new();
+    for w in words {
+      let just_down = w.ends_with('+');
+      let c = Control::from_name(w.trim_end_matches('+'))?;
+      controls.push((c, just_down));
+    }
+    Some(InputRecord { mouse_pos: (x, y), wheel, resized, controls })
+  }
+}
+
+/// Engine settings which change what the recorded input does, so a replay
+/// has to be played back with the same ones.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ReplaySettings {
+  pub edge_scroll: bool,
+  pub pan_speed: f32,
+  pub resize_policy: ResizePolicy,
+}
+
+/// Writes a replay file, one record per frame.
+pub struct ReplayRecorder {
+  out: BufWriter<File>,
+}
+
+impl ReplayRecorder {
+  /// Create the replay file and write its header. `screen_size` is the size
+  /// of the screen in pixels the input is being recorded on, `settings` are
+  /// the engine's, and `level` is the source of the level being played, if
+  /// any.
+  pub fn create<P: AsRef<Path>>(path: P, screen_size: (i32, i32), settings: ReplaySettings,
+                                level: Option<&str>) -> io::Result<ReplayRecorder> {
+    let mut out = BufWriter::new(File::create(path)?);
+    let level = level.unwrap_or("");
+    writeln!(out, "{} {} {} {} {} {:?} {:?} {}", REPLAY_MAGIC, REPLAY_VERSION, screen_size.0, screen_size.1,
+             settings.edge_scroll as u8, settings.pan_speed, settings.resize_policy, level.lines().count())?;
+    for line in level.lines() { writeln!(out, "{}", line)?; }
+    Ok(ReplayRecorder { out })
+  }
+
+  /// Write out everything recorded so far.
+  pub fn flush(&mut self) -> io::Result<()> { self.out.flush() }
+
+  /// Write the current frame's input.
+  pub fn record(&mut self, handler: &InputHandler) -> io::Result<()> {
+    InputRecord::from_handler(handler).write_line(&mut self.out)
+  }
+}
+
+/// A loaded replay file, played back one record per frame.
+pub struct Replay {
+  /// Size of the screen in pixels the replay was recorded on
+  pub screen_size: (i32, i32),
+  /// Settings of the engine the replay was recorded on
+  pub settings: ReplaySettings,
+  /// Source of the level the replay was recorded on, if there was one
+  pub level: Option<String>,
+  pub records: Vec<InputRecord>,
+  /// Index of the next record to play
+  next: usize,
+}
+
+impl Replay {
+  pub fn new(screen_size: (i32, i32), settings: ReplaySettings, level: Option<String>,
+             records: Vec<InputRecord>) -> Replay {
+    Replay { screen_size, settings, level, records, next: 0 }
+  }
+
+  pub fn load<P: AsRef<Path>>(path: P) -> io::Result<Replay> {
+    Replay::read(BufReader::new(File::open(path)?))
+  }
+
+  /// Parse a replay from a reader. Fails if the header is missing or for a
+  /// different version, or if any line is malformed.
+  pub fn read<R: BufRead>(reader: R) -> io::Result<Replay> {
+    let bad = |msg: String| io::Error::new(io::ErrorKind::InvalidData, msg);
+    let mut lines = reader.lines();
+    let header = match lines.next() {
+      Some(l) => l?,
+      None => return Err(bad("empty replay file".to_string())),
+    };
+    let mut words = header.split_whitespace();
+    if words.next() != Some(REPLAY_MAGIC) {
+      return Err(bad("not a replay file".to_string()));
+    }
+    let version: Option<u32> = words.next().and_then(|v| v.parse().ok());
+    if version != Some(REPLAY_VERSION) {
+      return Err(bad(format!("unsupported replay version (expected {})", REPLAY_VERSION)));
+    }
+    let w: Option<i32> = words.next().and_then(|v| v.parse().ok());
+    let h: Option<i32> = words.next().and_then(|v| v.parse().ok());
+    let (w, h) = match (w, h) {
+      (Some(w), Some(h)) => (w, h),
+      _ => return Err(bad("replay header is missing the screen size".to_string())),
+    };
+    let edge_scroll = match words.next() {
+      Some("0") => false,
+      Some("1") => true,
+      _ => return Err(bad("replay header is missing edge scrolling".to_string())),
+    };
+    let pan_speed = match words.next().and_then(|v| v.parse().ok()) {
+      Some(s) => s,
+      None => return Err(bad("replay header is missing the pan speed".to_string())),
+    };
+    let resize_policy = match words.next() {
+      Some("KeepScale") => ResizePolicy::KeepScale,
+      Some("FitView") => ResizePolicy::FitView,
+      _ => return Err(bad("replay header is missing the resize policy".to_string())),
+    };
+    let settings = ReplaySettings { edge_scroll, pan_speed, resize_policy };
+    let level_lines: usize = match words.next().and_then(|v| v.parse().ok()) {
+      Some(n) => n,
+      None => return Err(bad("replay header is missing the level's length".to_string())),
+    };
+    let mut level = None;
+    if level_lines > 0 {
+      let mut src = String::new();
+      for _ in 0..level_lines {
+        match lines.next() {
+          Some(l) => { src.push_str(&l?); src.push('\n'); },
+          None => return Err(bad("replay file ends inside its level".to_string())),
+        }
+      }
+      level = Some(src);
+    }
+    let mut records = Vec::new();
+    for (ii, line) in lines.enumerate() {
+      let line = line?;
+      match InputRecord::parse_line(&line) {
+        Some(r) => records.push(r),
+        // Lines are numbered from 1, after the header and the level
+        None => return Err(bad(format!("malformed replay record on line {}", ii + 2 + level_lines))),
+      }
+    }
+    Ok(Replay::new((w, h), settings, level, records))
+  }
+
+  /// Get the next frame's input, or None if the replay has finished.
+  pub fn next_record(&mut self) -> Option<&InputRecord> {
+    if self.next >= self.records.len() { return None; }
+    self.next += 1;
+    return Some(&self.records[self.next - 1]);
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::env;
+  use std::fs;
+  use std::process;
+  use glium::glutin::{Event, ElementState, MouseButton};
+  use engine::{Engine, Vec2f32};
+  use input::QueuedInput;
+  use level::Level;
+
+  const LEVEL: &str = "bounds 0 0 800 600\nbox 300 200 50 200 # wall\nspawn 100 300 4\nspawn 140 300 2\n";
+
+  #[test]
+  fn record_and_replay() {
+    let path = env::temp_dir().join(format!("splitterman-replay-test-{}", process::id()));
+    let input = QueuedInput::new();
+    let mut e = Engine::new_headless(800, 600, Box::new(input.clone()));
+    e.load_level(Level::parse(LEVEL).unwrap());
+    e.recorder = Some(ReplayRecorder::create(&path, (800, 600), e.replay_settings(), Some(LEVEL)).unwrap());
+    e.select_all();
+    for frame in 0..90 {
+      if frame == 3 {
+        input.push(Event::MouseMoved(600, 320));
+        input.push(Event::MouseInput(ElementState::Pressed, MouseButton::Right));
+      }
+      if frame == 4 { input.push(Event::MouseInput(ElementState::Released, MouseButton::Right)); }
+      assert!(!e.tick());
+    }
+    e.recorder.as_mut().unwrap().flush().unwrap();
+
+    // Everything needed to play it back is in the file
+    let replay = Replay::load(&path).unwrap();
+    fs::remove_file(&path).unwrap();
+    assert_eq!(replay.screen_size, (800, 600));
+    assert_eq!(replay.records.len(), 90);
+    let mut r = Engine::new_headless(replay.screen_size.0, replay.screen_size.1, Box::new(QueuedInput::new()));
+    r.load_level(Level::parse(replay.level.as_ref().unwrap()).unwrap());
+    r.select_all();
+    r.play_replay(replay);
+    while !r.tick() {}
+    assert_eq!(r.tick_count, e.tick_count);
+    assert_eq!(r.checksum(), e.checksum());
+  }
+
+  #[test]
+  fn replays_edge_scroll_like_the_recording() {
+    let path = env::temp_dir().join(format!("splitterman-replay-edge-test-{}", process::id()));
+    let level = "bounds 0 0 2000 2000\nspawn 1000 1000 2\n";
+    let input = QueuedInput::new();
+    let mut e = Engine::new_headless(800, 600, Box::new(input.clone()));
+    e.load_level(Level::parse(level).unwrap());
+    e.camera.centre_on(Vec2f32(1000.0, 1000.0));
+    e.edge_scroll = true;
+    e.pan_speed = 5.0;
+    e.resize_policy = ResizePolicy::FitView;
+    e.recorder = Some(ReplayRecorder::create(&path, (800, 600), e.replay_settings(), Some(level)).unwrap());
+    e.select_all();
+    for frame in 0..90 {
+      // Scroll left a while, then order a move to the middle of the screen
+      if frame == 3 { input.push(Event::MouseMoved(0, 300)); }
+      if frame == 30 { input.push(Event::MouseMoved(400, 300)); }
+      if frame == 31 { input.push(Event::MouseInput(ElementState::Pressed, MouseButton::Right)); }
+      if frame == 32 { input.push(Event::MouseInput(ElementState::Released, MouseButton::Right)); }
+      assert!(!e.tick());
+    }
+    e.recorder.as_mut().unwrap().flush().unwrap();
+    assert!(e.camera.pos().0 < 1000.0);
+
+    let replay = Replay::load(&path).unwrap();
+    fs::remove_file(&path).unwrap();
+    assert_eq!(replay.settings, e.replay_settings());
+    let mut r = Engine::new_headless(replay.screen_size.0, replay.screen_size.1, Box::new(QueuedInput::new()));
+    r.load_level(Level::parse(replay.level.as_ref().unwrap()).unwrap());
+    r.camera.centre_on(Vec2f32(1000.0, 1000.0));
+    r.select_all();
+    r.play_replay(replay);
+    while !r.tick() {}
+    assert_eq!(r.camera.pos(), e.camera.pos());
+    assert_eq!(r.checksum(), e.checksum());
+  }
+
+  #[test]
+  fn bad_record_line_counts_level() {
+    let src = "splitterman-replay 5 800 600 1 8.0 KeepScale 2\nbounds 0 0 800 600\nspawn 1 1 1\n0 0 0.0\n0 0 0.0 Nonsense\n";
+    let err = Replay::read(src.as_bytes()).err().unwrap();
+    assert!(err.to_string().contains("line 5"), "{}", err);
+    let ok = Replay::read("splitterman-replay 5 800 600 0 8.0 FitView 0\n1 2 0.0 Move+\n".as_bytes()).unwrap();
+    assert!(ok.level.is_none());
+    assert_eq!(ok.settings, ReplaySettings { edge_scroll: false, pan_speed: 8.0, resize_policy: ResizePolicy::FitView });
+    assert_eq!(ok.records[0].get(Control::Move), (true, true));
+  }
+}
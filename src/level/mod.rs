@@ -0,0 +1,284 @@
+use std::fmt;
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+use engine::Vec2f32;
+use engine::common::geometry::{Rect, ConvexPoly, Shape, MAX_POLY_VERTS};
+use entity::{SplitterMan, Obstacle, ObstacleKind, PressurePlate};
+
+// # Level file format
+// Levels are text, one item per line, with fields separated by spaces.
+// Anything after a `#` is a comment, and blank lines are ignored. Coords are
+// in world units.
+//
+// bounds <min x> <min y> <max x> <max y>   Edges of the map. Exactly one.
+// box <x> <y> <w> <h>                      Solid box obstacle.
+// poly <x> <y> <x> <y> <x> <y> ...         Solid convex polygon obstacle,
+//                                          3 to 8 vertices.
+// gap <x> <y> <w> <h> <max rad>            Box only splitter men with a
+//                                          radius below max rad can pass.
+// door <x> <y> <w> <h> <channel>           Box which opens while every plate
+//                                          on the channel is pressed.
+// plate <x> <y> <w> <h> <size> <channel>   Pressure plate, pressed while the
+//                                          splitter men on it add up to at
+//                                          least size.
+// spawn <x> <y> <size>                     Splitter man placed at the start.
+//                                          At least one.
+// goal <x> <y> <w> <h>                     Region splitter men need to reach.
+//
+// Spawned splitter men must be entirely inside the bounds and can't start
+// inside an obstacle that blocks them. Goals must be inside the bounds. Every
+// door's channel needs at least one plate.
+
+/// A splitter man placed at the start of a level.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Spawn {
+  pub pos: Vec2f32,
+  pub size: u32,
+}
+
+/// Everything described by a level file.
+#[derive(Clone, Debug)]
+pub struct Level {
+  pub bounds: Rect,
+  pub obstacles: Vec<Obstacle>,
+  pub plates: Vec<PressurePlate>,
+  pub spawns: Vec<Spawn>,
+  pub goals: Vec<Rect>,
+  /// The text the level was parsed from, so replays can carry it.
+  pub source: String,
+}
+
+/// A problem with a level file.
+#[derive(Clone, Debug, PartialEq)]
+pub struct LevelError {
+  /// Line the problem is on, counting from 1. 0 if the problem is with the
+  /// file as a whole.
+  pub line: usize,
+  pub msg: String,
+}
+
+impl fmt::Display for LevelError {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    if self.line == 0 { write!(f, "{}", self.msg) }
+    else { write!(f, "line {}: {}", self.line, self.msg) }
+  }
+}
+
+impl Level {
+  pub fn load<P: AsRef<Path>>(path: P) -> Result<Level, Vec<LevelError>> {
+    let mut src = String::new();
+    let res = File::open(path).and_then(|mut f| f.read_to_string(&mut src));
+    if let Err(e) = res {
+      return Err(vec![LevelError { line: 0, msg: format!("couldn't read level: {}", e) }]);
+    }
+    Level::parse(&src)
+  }
+
+  /// Parse and validate a level.
+  /// # Returns
+  /// The level, or every problem found with it.
+  pub fn parse(src: &str) -> Result<Level, Vec<LevelError>> {
+    let mut errors = Vec::new();
+    let mut bounds = None;
+    let mut obstacles = Vec::new();
+    let mut plates = Vec::new();
+    // Keep line numbers around for validating once everything is read
+    let mut spawns = Vec::new();
+    let mut goals = Vec::new();
+
+    for (ii, line) in src.lines().enumerate() {
+      let line_num = ii + 1;
+      let mut err = |msg: String| errors.push(LevelError { line: line_num, msg });
+      let line = match line.find('#') { Some(ix) => &line[..ix], None => line };
+      let mut words = line.split_whitespace();
+      let kind = match words.next() { Some(k) => k, None => continue };
+      let mut nums = Vec::new();
+      let mut bad_num = false;
+      for w in words {
+        match w.parse::<f32>() {
+          Ok(n) if n.is_finite() => nums.push(n),
+          _ => { err(format!("'{}' is not a number", w)); bad_num = true; },
+        }
+      }
+      if bad_num { continue; }
+      let expected = match kind {
+        "bounds" | "box" | "goal" => Some(4),
+        "gap" | "door" => Some(5),
+        "plate" => Some(6),
+        "spawn" => Some(3),
+        "poly" => None,
+        _ => { err(format!("unknown item '{}'", kind)); continue; },
+      };
+      if let Some(n) = expected.filter(|n| nums.len() != *n) {
+        err(format!("'{}' takes {} numbers, found {}", kind, n, nums.len()));
+        continue;
+      }
+      match kind {
+        "bounds" => {
+          if bounds.is_some() { err("bounds given more than once".to_string()); }
+          else if nums[2] <= nums[0] || nums[3] <= nums[1] {
+            err("bounds max must be greater than min".to_string());
+          }
+          else { bounds = Some(Rect::new(nums[0], nums[1], nums[2] - nums[0], nums[3] - nums[1])); }
+        },
+        "box" => {
+          if nums[2] <= 0.0 || nums[3] <= 0.0 { err("box must have a positive size".to_string()); }
+          else { obstacles.push((line_num, wall(Shape::Rect(Rect::new(nums[0], nums[1], nums[2], nums[3]))))); }
+        },
+        "gap" => {
+          if nums[2] <= 0.0 || nums[3] <= 0.0 { err("gap must have a positive size".to_string()); }
+          else if nums[4] <= 0.0 { err("gap max rad must be positive".to_string()); }
+          else {
+            let shape = Shape::Rect(Rect::new(nums[0], nums[1], nums[2], nums[3]));
+            obstacles.push((line_num, Obstacle::new(shape, ObstacleKind::Gap(nums[4]))));
+          }
+        },
+        "door" => {
+          if nums[2] <= 0.0 || nums[3] <= 0.0 { err("door must have a positive size".to_string()); }
+          else if !is_whole(nums[4], 0.0) { err("door channel must be a whole number".to_string()); }
+          else {
+            let shape = Shape::Rect(Rect::new(nums[0], nums[1], nums[2], nums[3]));
+            let kind = ObstacleKind::Door { channel: nums[4] as u32, open: false };
+            obstacles.push((line_num, Obstacle::new(shape, kind)));
+          }
+        },
+        "plate" => {
+          if nums[2] <= 0.0 || nums[3] <= 0.0 { err("plate must have a positive size".to_string()); }
+          else if !is_whole(nums[4], 1.0) { err("plate size must be a whole number of at least 1".to_string()); }
+          else if !is_whole(nums[5], 0.0) { err("plate channel must be a whole number".to_string()); }
+          else {
+            let area = Rect::new(nums[0], nums[1], nums[2], nums[3]);
+            plates.push(PressurePlate::new(area, nums[4] as u32, nums[5] as u32));
+          }
+        },
+        "poly" => {
+          if nums.len() % 2 != 0 { err("poly needs an x and y for every vertex".to_string()); continue; }
+          let points: Vec<Vec2f32> = nums.chunks(2).map(|c| Vec2f32(c[0], c[1])).collect();
+          if points.len() < 3 || points.len() > MAX_POLY_VERTS {
+            err(format!("poly needs 3 to {} vertices, found {}", MAX_POLY_VERTS, points.len()));
+            continue;
+          }
+          match ConvexPoly::new(&points) {
+            Some(p) => obstacles.push((line_num, wall(Shape::Poly(p)))),
+            None => err("poly must be convex".to_string()),
+          }
+        },
+        "spawn" => {
+          if !is_whole(nums[2], 1.0) {
+            err("spawn size must be a whole number of at least 1".to_string());
+          }
+          else { spawns.push((line_num, Spawn { pos: Vec2f32(nums[0], nums[1]), size: nums[2] as u32 })); }
+        },
+        "goal" => {
+          if nums[2] <= 0.0 || nums[3] <= 0.0 { err("goal must have a positive size".to_string()); }
+          else { goals.push((line_num, Rect::new(nums[0], nums[1], nums[2], nums[3]))); }
+        },
+        _ => unreachable!(),
+      }
+    }
+
+    if bounds.is_none() {
+      errors.push(LevelError { line: 0, msg: "level has no bounds".to_string() });
+    }
+    if spawns.is_empty() {
+      errors.push(LevelError { line: 0, msg: "level has no spawns".to_string() });
+    }
+    if let Some(b) = bounds {
+      for &(line, ref s) in &spawns {
+        if !b.contains_circle(s.pos, SplitterMan::calc_size(s.size)) {
+          errors.push(LevelError { line, msg: "spawn is not inside the bounds".to_string() });
+        }
+      }
+      for &(line, ref g) in &goals {
+        if !b.contains(g.pos) || !b.contains(g.max()) {
+          errors.push(LevelError { line, msg: "goal is not inside the bounds".to_string() });
+        }
+      }
+    }
+    for &(line, ref o) in &obstacles {
+      if let ObstacleKind::Door { channel, .. } = o.kind {
+        if !plates.iter().any(|p| p.channel == channel) {
+          errors.push(LevelError { line, msg: format!("no plates on door channel {}", channel) });
+        }
+      }
+    }
+    for &(line, ref s) in &spawns {
+      for &(o_line, ref o) in &obstacles {
+        if o.shape.contains(s.pos) && o.blocks(SplitterMan::calc_size(s.size)) {
+          errors.push(LevelError { line, msg: format!("spawn is inside the obstacle on line {}", o_line) });
+        }
+      }
+    }
+
+    if !errors.is_empty() {
+      errors.sort_by_key(|e| e.line);
+      return Err(errors);
+    }
+    Ok(Level {
+      bounds: bounds.unwrap(),
+      obstacles: obstacles.into_iter().map(|o| o.1).collect(),
+      plates,
+      spawns: spawns.into_iter().map(|s| s.1).collect(),
+      goals: goals.into_iter().map(|g| g.1).collect(),
+      source: src.to_string(),
+    })
+  }
+}
+
+fn wall(shape: Shape) -> Obstacle { Obstacle::new(shape, ObstacleKind::Wall) }
+
+/// True if n is a whole number of at least min.
+fn is_whole(n: f32, min: f32) -> bool { n >= min && n.fract() == 0.0 }
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn lines(src: &str) -> Vec<usize> {
+    Level::parse(src).unwrap_err().iter().map(|e| e.line).collect()
+  }
+
+  #[test]
+  fn example_level_parses() {
+    let level = Level::load("levels/example.txt").unwrap();
+    assert_eq!(level.spawns.len(), 1);
+    assert_eq!(level.plates.len(), 1);
+    assert_eq!(level.obstacles.len(), 5);
+  }
+
+  #[test]
+  fn errors_name_their_lines() {
+    let src = "bounds 0 0 800 600\n\
+               box 10 10 -5 5\n\
+               # comment\n\
+               spawn 400 300 two\n\
+               blob 1 2\n\
+               gap 1 2 3\n\
+               poly 0 0 10 0 10 10 0 10 5 -20\n\
+               spawn 100 100 1\n";
+    let errors = Level::parse(src).unwrap_err();
+    assert_eq!(errors.iter().map(|e| e.line).collect::<Vec<_>>(), vec![2, 4, 5, 6, 7]);
+    assert_eq!(errors[0].to_string(), "line 2: box must have a positive size");
+    assert_eq!(errors[2].to_string(), "line 5: unknown item 'blob'");
+    assert_eq!(errors[3].to_string(), "line 6: 'gap' takes 5 numbers, found 3");
+  }
+
+  #[test]
+  fn whole_level_problems_come_first() {
+    let errors = Level::parse("box 0 0 10 10\n").unwrap_err();
+    assert_eq!(errors.iter().map(|e| e.line).collect::<Vec<_>>(), vec![0, 0]);
+    assert_eq!(errors[0].to_string(), "level has no bounds");
+  }
+
+  #[test]
+  fn validation_points_at_the_item() {
+    // Spawn outside the bounds, goal outside the bounds, a door without
+    // plates and a spawn inside a wall
+    let src = "bounds 0 0 100 100\nspawn 500 500 1\ngoal 90 90 20 20\n\
+               door 0 0 10 10 3\nbox 40 40 20 20\nspawn 50 50 1\n";
+    assert_eq!(lines(src), vec![2, 3, 4, 6]);
+    let errors = Level::parse(src).unwrap_err();
+    assert_eq!(errors[3].msg, "spawn is inside the obstacle on line 5");
+  }
+}
@@ -0,0 +1,17 @@
+// The codebase prefers explicit returns, so keep clippy quiet about them.
+#![allow(unknown_lints)]
+#![allow(clippy::needless_return)]
+
+#[macro_use]
+extern crate glium;
+extern crate time;
+
+pub mod game_renderer;
+pub mod minimap;
+/// Handles shader programs. Not sure if I need this, just nice to keep all the
+/// hard coded shader source out the way.
+pub mod shader;
+pub mod entity;
+pub mod engine;
+pub mod input;
+pub mod level;
@@ -1,25 +1,76 @@
-#[macro_use]
-extern crate glium;
-extern crate time;
+extern crate splitterman;
 
-pub mod game_renderer;
-/// Handles shader programs. Not sure if I need this, just nice to keep all the
-/// hard coded shader source out the way.
-pub mod shader;
-pub mod entity;
-pub mod engine;
-pub mod input;
-
-use entity::Entity;
-use entity::SplitterMan;
+use std::env;
+use splitterman::engine;
+use splitterman::entity::Entity;
+use splitterman::entity::SplitterMan;
+use splitterman::input::{QueuedInput, Replay, ReplayRecorder};
+use splitterman::level::Level;
 
+const USAGE: &str = "usage: splitterman [--level <file>] [--record <file>] [--replay <file> [--headless]]";
 
 fn main() {
+  let mut level_path = None;
+  let mut record_path = None;
+  let mut replay_path = None;
+  let mut headless = false;
+  let mut args = env::args().skip(1);
+  while let Some(arg) = args.next() {
+    match arg.as_ref() {
+      "--level" => level_path = args.next(),
+      "--record" => record_path = args.next(),
+      "--replay" => replay_path = args.next(),
+      "--headless" => headless = true,
+      _ => { println!("{}", USAGE); return; }
+    }
+  }
+  if headless && replay_path.is_none() { println!("{}", USAGE); return; }
+
+  let replay = replay_path.map(|p| Replay::load(&p).expect("Couldn't load replay file"));
+  // Replays are played on the level they were recorded on
+  let level = match (replay.as_ref(), level_path) {
+    (Some(r), _) => r.level.as_ref().map(|src| ("replay".to_string(), Level::parse(src))),
+    (None, Some(p)) => { let l = Level::load(&p); Some((p, l)) },
+    (None, None) => None,
+  };
+  let level = match level {
+    Some((_, Ok(l))) => Some(l),
+    Some((name, Err(errors))) => {
+      for e in errors { println!("{}: {}", name, e); }
+      return;
+    },
+    None => None,
+  };
+
   // Create engine
-  let mut engine = engine::Engine::new();
+  let mut engine;
+  if headless {
+    let (w, h) = replay.as_ref().unwrap().screen_size;
+    engine = engine::Engine::new_headless(w, h, Box::new(QueuedInput::new()));
+  }
+  else { engine = engine::Engine::new(); }
+  if let Some(r) = replay { engine.play_replay(r); }
 
-  // Add test entity
-  engine.add_entity(Entity::SplitterMan(SplitterMan::new(100.0, 100.0, 64)));
+  match level {
+    Some(l) => engine.load_level(l),
+    // Add test entity
+    None => { engine.add_entity(Entity::SplitterMan(SplitterMan::new(100.0, 100.0, 64))); },
+  }
+
+  if let Some(p) = record_path {
+    let screen_size = engine.camera.screen_size();
+    let level = engine.level.as_ref().map(|l| l.source.as_str());
+    let settings = engine.replay_settings();
+    engine.recorder = Some(ReplayRecorder::create(&p, screen_size, settings, level).expect("Couldn't create replay file"));
+  }
+
+  if headless {
+    // Run the replay as fast as possible, then print the final state so runs
+    // can be compared
+    while !engine.tick() {}
+    println!("{} frames, checksum {:016x}", engine.tick_count, engine.checksum());
+    return;
+  }
 
   loop {
     if engine.update() { return }
@@ -0,0 +1,190 @@
+use engine::{Engine, Vec2f32};
+use engine::common::geometry::{Rect, Shape};
+use entity::Entity;
+use game_renderer::RendererController;
+
+/// Overview of the whole level, drawn in the bottom left corner of the HUD.
+/// Clicking on it moves the camera there, and ordering a move on it sends the
+/// selection there.
+#[derive(Clone, Copy, Debug)]
+pub struct Minimap {
+  /// Length of the longest side of the panel, in pixels. The other side
+  /// follows the shape of the level.
+  pub size: f32,
+  /// Gap between the panel and the edges of the screen, in pixels.
+  pub margin: f32,
+}
+
+impl Default for Minimap {
+  fn default() -> Minimap { Minimap::new() }
+}
+
+impl Minimap {
+  pub fn new() -> Minimap { Minimap { size: 160.0, margin: 8.0 } }
+
+  /// Where the panel is on a screen `screen` pixels big, showing a level
+  /// with the given bounds.
+  pub fn panel(&self, bounds: &Rect, screen: (i32, i32)) -> Rect {
+    let scale = self.scale(bounds);
+    let (w, h) = (bounds.size.0 * scale, bounds.size.1 * scale);
+    return Rect::new(self.margin, screen.1 as f32 - self.margin - h, w, h);
+  }
+
+  /// Pixels on the panel per world unit.
+  fn scale(&self, bounds: &Rect) -> f32 {
+    return (self.size / bounds.size.0).min(self.size / bounds.size.1);
+  }
+
+  /// Convert a world point to where it's drawn on the panel, in screen
+  /// coords.
+  pub fn world_to_panel(&self, bounds: &Rect, screen: (i32, i32), p: Vec2f32) -> Vec2f32 {
+    let panel = self.panel(bounds, screen);
+    return panel.pos + *(p - bounds.pos).scale(self.scale(bounds));
+  }
+
+  /// Convert a point in screen coords to the world point it shows.
+  /// # Returns
+  /// None if the point isn't on the panel.
+  pub fn panel_to_world(&self, bounds: &Rect, screen: (i32, i32), p: Vec2f32) -> Option<Vec2f32> {
+    let panel = self.panel(bounds, screen);
+    if !panel.contains(p) { return None; }
+    return Some(bounds.pos + *(p - panel.pos).scale(1.0 / self.scale(bounds)));
+  }
+
+  /// Draw the panel, with the level's obstacles, every splitter man and the
+  /// camera's viewport, in screen coords. Draws nothing without a level.
+  pub fn render(&self, e: &Engine, cont: &mut RendererController) {
+    let bounds = match e.level { Some(ref l) => l.bounds, None => return };
+    let screen = e.camera.screen_size();
+    let panel = self.panel(&bounds, screen);
+    let scale = self.scale(&bounds);
+    let to_panel = |p: Vec2f32| self.world_to_panel(&bounds, screen, p);
+
+    cont.rect(panel.pos.0, panel.pos.1, panel.size.0, panel.size.1, 0.0, 0.0, 0.0, 0.7);
+    outline(cont, panel.pos, panel.max(), 0.5, 0.5, 0.5, 1.0);
+
+    for ent in &e.entity_list {
+      match ent.get() {
+        Entity::Obstacle(o) => {
+          let c = o.colour();
+          match o.shape {
+            Shape::Rect(ref r) => {
+              let p = to_panel(r.pos);
+              cont.rect(p.0, p.1, r.size.0 * scale, r.size.1 * scale, c.0, c.1, c.2, c.3);
+            },
+            Shape::Poly(ref poly) => {
+              let verts: Vec<Vec2f32> = poly.verts().iter().map(|v| to_panel(*v)).collect();
+              cont.poly(&verts, c.0, c.1, c.2, c.3);
+            },
+          }
+        },
+        Entity::SplitterMan(s) => {
+          // Never too small to see
+          let rad = (s.get_size() * scale).max(1.5);
+          let p = to_panel(s.body.pos);
+          let c = s.colour();
+          cont.rect(p.0 - rad, p.1 - rad, rad*2.0, rad*2.0, c.0, c.1, c.2, c.3);
+        },
+        _ => (),
+      }
+    }
+
+    // Viewport, cut down to the panel
+    let half = *e.camera.size().scale(0.5);
+    let (min, max) = (to_panel(e.camera.pos() - half), to_panel(e.camera.pos() + half));
+    let min = Vec2f32(min.0.max(panel.pos.0), min.1.max(panel.pos.1));
+    let max = Vec2f32(max.0.min(panel.max().0), max.1.min(panel.max().1));
+    outline(cont, min, max, 1.0, 1.0, 1.0, 1.0);
+  }
+}
+
+/// Draw the outline of a box a pixel wide, inside the box.
+fn outline(cont: &mut RendererController, min: Vec2f32, max: Vec2f32, r: f32, g: f32, b: f32, a: f32) {
+  let (w, h) = (max.0 - min.0, max.1 - min.1);
+  cont.rect(min.0, min.1, w, 1.0, r, g, b, a);
+  cont.rect(min.0, max.1 - 1.0, w, 1.0, r, g, b, a);
+  cont.rect(min.0, min.1, 1.0, h, r, g, b, a);
+  cont.rect(max.0 - 1.0, min.1, 1.0, h, r, g, b, a);
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use engine::Engine;
+  use input::QueuedInput;
+  use level::Level;
+
+  fn close(a: Vec2f32, b: Vec2f32) -> bool { (a - b).len() < 0.01 }
+
+  fn engine() -> Engine {
+    let mut e = Engine::new_headless(800, 600, Box::new(QueuedInput::new()));
+    e.load_level(Level::parse("bounds 0 0 1600 800\nbox 400 0 100 200\nspawn 800 400 4\n").unwrap());
+    return e;
+  }
+
+  #[test]
+  fn panel_keeps_level_shape() {
+    let m = Minimap::new();
+    let panel = m.panel(&Rect::new(0.0, 0.0, 1600.0, 800.0), (800, 600));
+    assert!(close(panel.size, Vec2f32(160.0, 80.0)));
+    assert!(close(panel.pos, Vec2f32(8.0, 600.0 - 8.0 - 80.0)));
+  }
+
+  #[test]
+  fn panel_world_round_trip() {
+    let m = Minimap::new();
+    let bounds = Rect::new(-400.0, -300.0, 1200.0, 500.0);
+    for &p in &[Vec2f32(-400.0, -300.0), Vec2f32(0.0, 0.0), Vec2f32(799.0, 199.0)] {
+      let s = m.world_to_panel(&bounds, (1024, 768), p);
+      assert!(close(m.panel_to_world(&bounds, (1024, 768), s).unwrap(), p));
+    }
+    assert!(m.panel_to_world(&bounds, (1024, 768), Vec2f32(500.0, 100.0)).is_none());
+  }
+
+  #[test]
+  fn draws_everything_inside_the_panel() {
+    let e = engine();
+    let mut cont = RendererController::new(0);
+    e.minimap.render(&e, &mut cont);
+    let panel = e.minimap.panel(&e.level.as_ref().unwrap().bounds, e.camera.screen_size());
+    assert!(!cont.vertices().is_empty());
+    for v in cont.vertices() {
+      assert!(panel.contains(Vec2f32(v.pos[0], v.pos[1])), "{:?} outside {:?}", v.pos, panel);
+    }
+  }
+
+  #[test]
+  fn draws_units_and_obstacles_where_they_are() {
+    let e = engine();
+    let mut cont = RendererController::new(0);
+    e.minimap.render(&e, &mut cont);
+    let bounds = e.level.as_ref().unwrap().bounds;
+    let screen = e.camera.screen_size();
+    // The splitter man is drawn in his colour, centred where he is
+    let unit = e.minimap.world_to_panel(&bounds, screen, Vec2f32(800.0, 400.0));
+    let red: Vec<&[f32; 2]> = cont.vertices().iter().filter(|v| v.col == [1.0, 0.0, 0.0, 1.0]).map(|v| &v.pos).collect();
+    assert_eq!(red.len(), 6);
+    let mut centre = Vec2f32(0.0, 0.0);
+    for p in &red { centre += Vec2f32(p[0], p[1]); }
+    assert!(close(*centre.scale(1.0 / 6.0), unit));
+    // The wall's corner is drawn where it is
+    let corner = e.minimap.world_to_panel(&bounds, screen, Vec2f32(400.0, 0.0));
+    assert!(cont.vertices().iter().any(|v| v.col == [0.4, 0.4, 0.45, 1.0] && close(Vec2f32(v.pos[0], v.pos[1]), corner)));
+  }
+
+  #[test]
+  fn draws_viewport() {
+    let mut e = engine();
+    e.camera.centre_on(Vec2f32(800.0, 400.0));
+    let mut cont = RendererController::new(0);
+    e.minimap.render(&e, &mut cont);
+    let bounds = e.level.as_ref().unwrap().bounds;
+    let screen = e.camera.screen_size();
+    let min = e.minimap.world_to_panel(&bounds, screen, Vec2f32(400.0, 100.0));
+    let max = e.minimap.world_to_panel(&bounds, screen, Vec2f32(1200.0, 700.0));
+    let white: Vec<Vec2f32> = cont.vertices().iter().filter(|v| v.col == [1.0, 1.0, 1.0, 1.0]).map(|v| Vec2f32(v.pos[0], v.pos[1])).collect();
+    assert_eq!(white.len(), 24);
+    assert!(white.iter().any(|p| close(*p, min)));
+    assert!(white.iter().any(|p| close(*p, max)));
+  }
+}
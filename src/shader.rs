@@ -1,7 +1,7 @@
 use glium;
 use glium::backend::glutin_backend::GlutinFacade;
 
-pub const VERT_SHADER_SRC : &'static str = r#"
+pub const VERT_SHADER_SRC : &str = r#"
   #version 130
 
   uniform mat4 proj_mat;
@@ -17,7 +17,7 @@ pub const VERT_SHADER_SRC : &'static str = r#"
   }
 "#;
 
-pub const FRAG_SHADER_SRC : &'static str = r#"
+pub const FRAG_SHADER_SRC : &str = r#"
   #version 130
   precision highp float;
 